@@ -1,19 +1,78 @@
+use std::thread::{self, JoinHandle};
+
 use onitama_move_gen::gen::Game;
-use rand::random;
+use rand::{random, seq::SliceRandom, thread_rng};
 use tensor::*;
 
-use crate::{convert::game_to_input, mcts::Node, network::Network, rand_game::random_game};
+use crate::{
+    convert::game_to_input,
+    mcts::{search, Budget, Exploration, FirstPlayUrgency, Node, SearchConfig},
+    moves::{legal_mask, POLICY_SIZE},
+    network::{CacheStats, Loss, Network},
+    rand_game::random_game,
+    replay_buffer::{ReplayBuffer, Window},
+    transposition::TranspositionTable,
+};
 
+// Network
+// The channels are part of the type, the blocks of a new network are saved
+// with it.
+pub const CHANNELS: usize = 64;
+pub const BLOCKS: usize = 6;
+// Threads
+// Self-play and pit games are split evenly between this many workers.
+const THREADS: u32 = 8;
+const WORKER_STACK_SIZE: usize = 1024 * 1024 * 1024 * 32;
 // Self-play
 const GAMES_PER_BATCH: u32 = 500;
-const ROLLOUTS_PER_MOVE: u32 = 100;
+const BUDGET: Budget = Budget {
+    rollouts: 100,
+    time: None,
+    stop_early: false,
+};
+pub const SEARCH_CONFIG: SearchConfig = SearchConfig {
+    exploration: Exploration::LogGrowth {
+        c_base: 19652.,
+        c_init: 1.25,
+    },
+    first_play_urgency: FirstPlayUrgency::Reduction(0.25),
+    batch_size: 8,
+};
+// Noise added to the root priors in self-play only.
+const DIRICHLET_ALPHA: f64 = 0.5;
+const DIRICHLET_EPSILON: f64 = 0.25;
+// Sample moves in proportion to visits for this many plies, then play the best.
+const TEMPERATURE_PLIES: u32 = 10;
+// Every worker keeps a transposition table across its self-play games.
+const TRANSPOSITION_BUCKETS: usize = 1 << 16;
+const SHARE_STATISTICS: bool = false;
+// Replay buffer
+pub const REPLAY_WINDOW: Window = Window::Generations(20);
+const SAMPLES_PER_GENERATION: usize = 20_000;
 // Training
+const SCHEDULE: Schedule = Schedule::Warmup {
+    steps: 1_000,
+    then: &Schedule::Step {
+        initial: 0.0001,
+        gamma: 0.1,
+        step_size: 200_000,
+    },
+};
+pub const MOMENTUM: f64 = 0.9;
+const WEIGHT_DECAY: f64 = 0.0001;
+const EPOCHS: u32 = 4;
+const BATCH_SIZE: usize = 32;
 const PIT_GAMES: u32 = 100;
+// Only the best move matters in the pit, so searches can end once it is clear.
+const PIT_BUDGET: Budget = Budget {
+    stop_early: true,
+    ..BUDGET
+};
 const WIN_RATE_THRESHOLD: f64 = 0.55;
 
 struct IncompleteTrainingExample {
     game: Game,
-    improved_policy: Vector<f64, 625>,
+    improved_policy: Vector<f64, POLICY_SIZE>,
 }
 
 impl IncompleteTrainingExample {
@@ -26,34 +85,82 @@ impl IncompleteTrainingExample {
     }
 }
 
-struct TrainingExample {
-    game: Game,
-    improved_policy: Vector<f64, 625>,
-    result: f64,
+pub struct TrainingExample {
+    pub game: Game,
+    pub improved_policy: Vector<f64, POLICY_SIZE>,
+    pub result: f64,
+}
+
+/// Number of games each worker should play so that they add up to `games`.
+fn games_per_thread(games: u32) -> impl Iterator<Item = u32> {
+    (0..THREADS).map(move |i| games / THREADS + (i < games % THREADS) as u32)
 }
 
-fn self_play(network: &Network) -> Vec<TrainingExample> {
+/// Run `f` on a new thread with a stack large enough for the network.
+fn spawn_worker<T, F>(f: F) -> JoinHandle<T>
+where
+    T: 'static + Send,
+    F: 'static + Send + FnOnce() -> T,
+{
+    thread::Builder::new()
+        .stack_size(WORKER_STACK_SIZE)
+        .spawn(f)
+        .expect("couldn't spawn worker thread")
+}
+
+/// Play self-play games on all workers and merge their examples.
+/// Every worker gets its own copy of the network, and with it its own cache
+/// of evaluations.
+fn self_play(network: &Network<CHANNELS>) -> Vec<TrainingExample> {
+    let workers: Vec<_> = games_per_thread(GAMES_PER_BATCH)
+        .map(|games| {
+            let network = network.clone();
+            spawn_worker(move || self_play_games(&network, games))
+        })
+        .collect();
     let mut training = Vec::new();
+    let mut cache_stats = CacheStats::default();
+    for worker in workers {
+        let (examples, stats) = worker.join().expect("self-play worker panicked");
+        training.extend(examples);
+        cache_stats += stats;
+    }
+    println!(
+        "self-play: {} examples, eval cache hit rate {:.1}%",
+        training.len(),
+        100. * cache_stats.hit_rate()
+    );
+    training
+}
+
+/// Also returns how often the evaluations came from the cache.
+fn self_play_games(network: &Network<CHANNELS>, games: u32) -> (Vec<TrainingExample>, CacheStats) {
+    let mut training = Vec::new();
+    let mut table = TranspositionTable::new(TRANSPOSITION_BUCKETS, SHARE_STATISTICS);
 
     // Run multiple games against self.
-    for _ in 0..GAMES_PER_BATCH {
+    for _ in 0..games {
         let mut game_training = Vec::new();
         // Create and play out a game while keeping track of examples.
-        let mut node = Node::random();
-        loop {
+        let mut node = Node::with_table(random_game(), table);
+        for ply in 0.. {
             if node.game.is_loss() {
                 break;
             }
-            for _ in 0..ROLLOUTS_PER_MOVE {
-                node.rollout(network);
-            }
+            // The first rollout makes sure the root is expanded.
+            node.rollout(network, &SEARCH_CONFIG);
+            node.add_exploration_noise(DIRICHLET_ALPHA, DIRICHLET_EPSILON);
+            let result = search(&mut node, network, &SEARCH_CONFIG, BUDGET);
             game_training.push(IncompleteTrainingExample {
                 game: node.game,
-                improved_policy: Vector::new(node.improved_policy()),
+                improved_policy: Vector::new(result.policy),
             });
-            let move_index = node.pick_move();
+            // The recorded policy is the visit distribution at any temperature.
+            let temperature = if ply < TEMPERATURE_PLIES { 1. } else { 0. };
+            let move_index = node.pick_move(temperature);
             node = node.step(move_index);
         }
+        table = node.into_table().unwrap();
 
         // Go through incomplete examples and fill in the game result.
         let mut val = 1.;
@@ -64,7 +171,7 @@ fn self_play(network: &Network) -> Vec<TrainingExample> {
         }
     }
 
-    training
+    (training, network.cache_stats())
 }
 
 struct PitResult {
@@ -79,30 +186,42 @@ impl PitResult {
 }
 
 /// Pits two networks against each other.
+/// Both play deterministically, always picking their most visited move.
 /// Counts wins and losses of the new network.
-fn pit(new: &Network, old: &Network) -> PitResult {
+fn pit(new: &Network<CHANNELS>, old: &Network<CHANNELS>) -> PitResult {
+    let workers: Vec<_> = games_per_thread(PIT_GAMES)
+        .map(|games| {
+            let new = new.clone();
+            let old = old.clone();
+            spawn_worker(move || pit_games(&new, &old, games))
+        })
+        .collect();
+    let mut result = PitResult { wins: 0, losses: 0 };
+    for worker in workers {
+        let PitResult { wins, losses } = worker.join().expect("pit worker panicked");
+        result.wins += wins;
+        result.losses += losses;
+    }
+    result
+}
+
+fn pit_games(new: &Network<CHANNELS>, old: &Network<CHANNELS>, games: u32) -> PitResult {
     let mut wins = 0;
     let mut losses = 0;
 
-    for _ in 0..PIT_GAMES {
+    for _ in 0..games {
         let mut game = random_game();
         let mut my_turn = random();
         let mut my_node = Node::from(game);
         let mut opp_node = Node::from(game);
         while !game.is_loss() {
             if my_turn {
-                for _ in 0..ROLLOUTS_PER_MOVE {
-                    my_node.rollout(new);
-                }
-                let move_index = my_node.pick_move();
+                let move_index = search(&mut my_node, new, &SEARCH_CONFIG, PIT_BUDGET).best_move;
                 my_node = my_node.step(move_index);
                 opp_node = opp_node.step(move_index);
                 game = my_node.game;
             } else {
-                for _ in 0..ROLLOUTS_PER_MOVE {
-                    opp_node.rollout(old);
-                }
-                let move_index = opp_node.pick_move();
+                let move_index = search(&mut opp_node, old, &SEARCH_CONFIG, PIT_BUDGET).best_move;
                 opp_node = opp_node.step(move_index);
                 my_node = my_node.step(move_index);
                 game = opp_node.game;
@@ -119,20 +238,76 @@ fn pit(new: &Network, old: &Network) -> PitResult {
     PitResult { wins, losses }
 }
 
-pub fn train_network(network: &mut Network) {
-    loop {
-        let training = self_play(&network);
-        let mut new_network = *network;
-        for example in training.into_iter() {
-            new_network.back_prop(
-                game_to_input(&example.game),
-                example.improved_policy,
-                example.result,
-            );
+/// Train on shuffled mini-batches for a few epochs.
+fn train<O: Optimizer>(
+    network: &mut Network<CHANNELS>,
+    optimizer: &mut O,
+    mut examples: Vec<&TrainingExample>,
+) {
+    let mut rng = thread_rng();
+    for epoch in 0..EPOCHS {
+        examples.shuffle(&mut rng);
+        let mut loss = Loss::default();
+        for batch in examples.chunks(BATCH_SIZE) {
+            let batch: Vec<_> = batch
+                .iter()
+                .map(|example| {
+                    (
+                        game_to_input(&example.game),
+                        legal_mask(&example.game),
+                        example.improved_policy,
+                        example.result,
+                    )
+                })
+                .collect();
+            // The schedule is indexed by the global step kept by the optimizer.
+            optimizer.set_learning_rate(SCHEDULE.learning_rate(optimizer.steps()));
+            loss += network.back_prop_batch(&batch, optimizer, WEIGHT_DECAY);
         }
+        let n = examples.len() as f64;
+        println!(
+            "epoch {}: policy loss {:.4}, value loss {:.4}, l2 {:.4}",
+            epoch,
+            loss.policy / n,
+            loss.value / n,
+            loss.regularization / n
+        );
+    }
+}
+
+pub fn train_network<O: Optimizer + Clone>(
+    network: &mut Network<CHANNELS>,
+    optimizer: &mut O,
+    replay_buffer: &mut ReplayBuffer,
+) {
+    loop {
+        replay_buffer.push_generation(self_play(&network));
+        let mut new_network = network.clone();
+        let mut new_optimizer = optimizer.clone();
+        train(
+            &mut new_network,
+            &mut new_optimizer,
+            replay_buffer.sample(SAMPLES_PER_GENERATION),
+        );
         if pit(&new_network, &network).win_rate() > WIN_RATE_THRESHOLD {
             *network = new_network;
+            *optimizer = new_optimizer;
             return;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn games_per_thread_adds_up() {
+        for games in [0, 1, THREADS - 1, THREADS, 500, 101] {
+            let split: Vec<_> = games_per_thread(games).collect();
+            assert_eq!(split.len(), THREADS as usize);
+            assert_eq!(split.iter().sum::<u32>(), games);
+            assert!(split.iter().max().unwrap() - split.iter().min().unwrap() <= 1);
+        }
+    }
+}
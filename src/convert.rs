@@ -9,6 +9,14 @@ const fn card_bitmap(x: u32) -> u32 {
     SHIFTED[x as usize][12]
 }
 
+// The fields of a Game.
+pub type GameKey = (u32, u32, u32, u32);
+
+/// The whole position, for maps keyed by positions.
+pub fn game_key(game: &Game) -> GameKey {
+    (game.my, game.other, game.cards, game.table)
+}
+
 /// Convert game struct to input tensor for nn.
 pub fn game_to_input(game: &Game) -> Tensor3<f64, 5, 5, 8> {
     let mut data = [0.; 5 * 5 * 8];
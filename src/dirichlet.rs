@@ -0,0 +1,66 @@
+use std::f64::consts::PI;
+
+use rand::{distributions::Open01, thread_rng, Rng};
+
+/// Sample from a symmetric Dirichlet distribution with `n` components.
+pub fn dirichlet(alpha: f64, n: usize) -> Vec<f64> {
+    let mut rng = thread_rng();
+    let samples: Vec<f64> = (0..n).map(|_| gamma(alpha, &mut rng)).collect();
+    let sum: f64 = samples.iter().sum();
+    samples.into_iter().map(|x| x / sum).collect()
+}
+
+/// Sample from a Gamma(shape, 1) distribution.
+/// Uses the method by Marsaglia and Tsang.
+fn gamma<R: Rng>(shape: f64, rng: &mut R) -> f64 {
+    if shape < 1. {
+        // Sample with shape + 1 and correct using a uniform sample.
+        let u: f64 = rng.sample(Open01);
+        return gamma(shape + 1., rng) * u.powf(1. / shape);
+    }
+    let d = shape - 1. / 3.;
+    let c = 1. / (9. * d).sqrt();
+    loop {
+        let x = standard_normal(rng);
+        let v = (1. + c * x).powi(3);
+        if v <= 0. {
+            continue;
+        }
+        let u: f64 = rng.sample(Open01);
+        if u.ln() < 0.5 * x * x + d - d * v + d * v.ln() {
+            return d * v;
+        }
+    }
+}
+
+/// Sample from a standard normal distribution with the Box-Muller transform.
+fn standard_normal<R: Rng>(rng: &mut R) -> f64 {
+    let u1: f64 = rng.sample(Open01);
+    let u2: f64 = rng.sample(Open01);
+    (-2. * u1.ln()).sqrt() * (2. * PI * u2).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sums_to_one() {
+        for &alpha in [0.03, 0.3, 1., 5.].iter() {
+            let noise = dirichlet(alpha, 10);
+            assert_eq!(noise.len(), 10);
+            assert!(noise.iter().all(|&x| x >= 0.));
+            assert!((noise.iter().sum::<f64>() - 1.).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn gamma_mean() {
+        // The mean of Gamma(k, 1) is k.
+        let mut rng = thread_rng();
+        for &shape in [0.5, 2.].iter() {
+            let mean = (0..100_000).map(|_| gamma(shape, &mut rng)).sum::<f64>() / 100_000.;
+            assert!((mean - shape).abs() < 0.05);
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use std::{collections::HashMap, hash::Hash};
+
+const NONE: usize = usize::MAX;
+
+struct Slot<K, V> {
+    key: K,
+    value: V,
+    /// Neighbours in the recency list.
+    prev: usize,
+    next: usize,
+}
+
+/// Map of bounded size which evicts the least recently used entry.
+/// Entries are kept in a doubly linked list threaded through a `Vec`,
+/// so lookups, inserts and evictions are all constant time.
+pub struct LruCache<K, V> {
+    capacity: usize,
+    map: HashMap<K, usize>,
+    slots: Vec<Slot<K, V>>,
+    /// Most recently used slot.
+    head: usize,
+    /// Least recently used slot.
+    tail: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl<K: Hash + Eq + Copy, V> LruCache<K, V> {
+    pub fn new(capacity: usize) -> LruCache<K, V> {
+        assert!(capacity > 0);
+        LruCache {
+            capacity,
+            map: HashMap::with_capacity(capacity),
+            slots: Vec::with_capacity(capacity),
+            head: NONE,
+            tail: NONE,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Remove all entries. The hit and miss counters are kept.
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.slots.clear();
+        self.head = NONE;
+        self.tail = NONE;
+    }
+
+    fn detach(&mut self, index: usize) {
+        let (prev, next) = (self.slots[index].prev, self.slots[index].next);
+        match prev {
+            NONE => self.head = next,
+            prev => self.slots[prev].next = next,
+        }
+        match next {
+            NONE => self.tail = prev,
+            next => self.slots[next].prev = prev,
+        }
+    }
+
+    fn push_front(&mut self, index: usize) {
+        self.slots[index].prev = NONE;
+        self.slots[index].next = self.head;
+        match self.head {
+            NONE => self.tail = index,
+            head => self.slots[head].prev = index,
+        }
+        self.head = index;
+    }
+
+    /// Look up a value and mark it as recently used.
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        match self.map.get(key).copied() {
+            Some(index) => {
+                self.hits += 1;
+                self.detach(index);
+                self.push_front(index);
+                Some(&self.slots[index].value)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Insert a value, evicting the least recently used one when full.
+    pub fn insert(&mut self, key: K, value: V) {
+        let index = if let Some(&index) = self.map.get(&key) {
+            self.slots[index].value = value;
+            self.detach(index);
+            index
+        } else if self.slots.len() < self.capacity {
+            self.slots.push(Slot {
+                key,
+                value,
+                prev: NONE,
+                next: NONE,
+            });
+            self.map.insert(key, self.slots.len() - 1);
+            self.slots.len() - 1
+        } else {
+            let index = self.tail;
+            self.detach(index);
+            self.map.remove(&self.slots[index].key);
+            self.map.insert(key, index);
+            self.slots[index].key = key;
+            self.slots[index].value = value;
+            index
+        };
+        self.push_front(index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        cache.insert(1, 'a');
+        cache.insert(2, 'b');
+        assert_eq!(cache.get(&1), Some(&'a'));
+        cache.insert(3, 'c');
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some(&'a'));
+        assert_eq!(cache.get(&3), Some(&'c'));
+        cache.insert(4, 'd');
+        assert_eq!(cache.get(&1), None);
+        assert_eq!((cache.hits(), cache.misses()), (3, 2));
+    }
+
+    #[test]
+    fn insert_existing_key() {
+        let mut cache = LruCache::new(2);
+        cache.insert(1, 'a');
+        cache.insert(2, 'b');
+        cache.insert(1, 'c');
+        cache.insert(3, 'd');
+        assert_eq!(cache.get(&1), Some(&'c'));
+        assert_eq!(cache.get(&2), None);
+        cache.clear();
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.get(&1), None);
+    }
+}
@@ -10,35 +10,148 @@ extern crate test;
 
 mod alpha_zero;
 mod convert;
+mod dirichlet;
+mod lru;
 mod mcts;
+mod model_file;
+mod moves;
 mod network;
+mod onnx;
 mod rand_game;
+mod replay_buffer;
+mod transposition;
 
-use std::{env, thread};
+use std::{env, fs, path::Path, str::FromStr, thread};
 
-use alpha_zero::train_network;
-use network::Network;
+use alpha_zero::{train_network, BLOCKS, CHANNELS, MOMENTUM, REPLAY_WINDOW, SEARCH_CONFIG};
+use mcts::{search, Budget, Node};
+use network::{convert_legacy, load_optimizer, save_optimizer, Network};
+use onitama_move_gen::gen::Game;
+use replay_buffer::ReplayBuffer;
+use tensor::Momentum;
+
+fn parse_arg<T: FromStr>(args: &mut env::Args, name: &str) -> T {
+    let arg = args.next().unwrap_or_else(|| panic!("missing argument: {}", name));
+    arg.parse().unwrap_or_else(|_| panic!("invalid {}: {}", name, arg))
+}
+
+fn load_network(iteration: u32) -> Network<CHANNELS> {
+    let path = format!("iters/alphazero_{:0>8}.data", iteration);
+    Network::load(&path).unwrap_or_else(|err| panic!("couldn't load {}: {}", path, err))
+}
+
+/// Search a position and write the tree to a DOT file.
+/// Usage: dot <iteration> <my> <other> <cards> <table> <rollouts> <file>
+/// [max depth] [min visits] [moves]
+/// The position is given by the fields of `Game`. Moves in notation like
+/// `a1b2,c3c4` are played from it before the search.
+fn export_dot(mut args: env::Args) {
+    let load: u32 = parse_arg(&mut args, "iteration");
+    let mut game = Game {
+        my: parse_arg(&mut args, "my"),
+        other: parse_arg(&mut args, "other"),
+        cards: parse_arg(&mut args, "cards"),
+        table: parse_arg(&mut args, "table"),
+    };
+    let rollouts = parse_arg(&mut args, "rollouts");
+    let path: String = parse_arg(&mut args, "file");
+    let max_depth = args.next().map_or(usize::MAX, |x| x.parse().expect("invalid max depth"));
+    let min_visits = args.next().map_or(1, |x| x.parse().expect("invalid min visits"));
+    let played = args.next().unwrap_or_default();
+    for notation in played.split(',').filter(|notation| !notation.is_empty()) {
+        let move_index =
+            moves::from_notation(notation).unwrap_or_else(|| panic!("invalid move: {}", notation));
+        game = moves::play(&game, move_index).unwrap_or_else(|| panic!("illegal move: {}", notation));
+    }
+
+    let network = load_network(load);
+    let mut node = Node::from(game);
+    let budget = Budget {
+        rollouts,
+        time: None,
+        stop_early: false,
+    };
+    let result = search(&mut node, &network, &SEARCH_CONFIG, budget);
+    node.dump(&SEARCH_CONFIG);
+    let variation: Vec<_> = result.principal_variation.iter().map(|&m| moves::to_notation(m)).collect();
+    println!(
+        "value {:.3}, principal variation {}, {} rollouts at {:.0} nodes/s",
+        result.value,
+        variation.join(" "),
+        result.rollouts,
+        result.nodes_per_second
+    );
+    node.save_dot(&path, max_depth, min_visits);
+}
+
+/// Convert a network saved before the residual tower to the current format.
+/// Usage: migrate <old file> <new file>
+fn migrate(mut args: env::Args) {
+    let from: String = parse_arg(&mut args, "old file");
+    let to: String = parse_arg(&mut args, "new file");
+    let data = fs::read(&from).unwrap_or_else(|err| panic!("couldn't read {}: {}", from, err));
+    let converted = convert_legacy(&data).unwrap_or_else(|err| panic!("couldn't convert {}: {}", from, err));
+    fs::write(&to, converted).expect("couldn't save network to file");
+}
+
+/// Export a saved network to ONNX.
+/// Usage: onnx <iteration> <file>
+fn export_onnx(mut args: env::Args) {
+    let load: u32 = parse_arg(&mut args, "iteration");
+    let path: String = parse_arg(&mut args, "file");
+    load_network(load).save_onnx(&path);
+}
 
 fn run() {
     // Look at the second argument to see if we should load.
     let mut args = env::args();
     let _ = args.next(); // First arg will just be the name of the binary.
-    let second_arg = args.next().map(|x| x.parse::<u32>());
+    let second_arg = args.next();
+    if second_arg.as_deref() == Some("dot") {
+        return export_dot(args);
+    }
+    if second_arg.as_deref() == Some("onnx") {
+        return export_onnx(args);
+    }
+    if second_arg.as_deref() == Some("migrate") {
+        return migrate(args);
+    }
+    let second_arg = second_arg.map(|x| x.parse::<u32>());
 
     let mut i = 0;
-    let mut network = match second_arg {
+    // The learning rate is set from the schedule before every step.
+    let mut optimizer = Momentum::new(0., MOMENTUM, false);
+    let (mut network, mut replay_buffer) = match second_arg {
         Some(Ok(load)) if load > 0 => {
             i = load + 1;
-            Network::load(&format!("iters/alphazero_{:>0}.data", load))
+            let network = load_network(load);
+            // Older checkpoints were saved without a replay buffer.
+            let replay_path = format!("iters/replay_{:0>8}.data", load);
+            let replay_buffer = if Path::new(&replay_path).exists() {
+                // An incompatible buffer is dropped, self-play fills a new one.
+                ReplayBuffer::load(&replay_path, REPLAY_WINDOW).unwrap_or_else(|err| {
+                    println!("ignoring replay buffer {}: {}", replay_path, err);
+                    ReplayBuffer::new(REPLAY_WINDOW)
+                })
+            } else {
+                ReplayBuffer::new(REPLAY_WINDOW)
+            };
+            let optimizer_path = format!("iters/optimizer_{:0>8}.data", load);
+            if Path::new(&optimizer_path).exists() {
+                load_optimizer(&mut optimizer, &optimizer_path);
+            }
+            (network, replay_buffer)
         }
-        _ => Network::init(),
+        _ => (Network::init(BLOCKS), ReplayBuffer::new(REPLAY_WINDOW)),
     };
 
     // Main training loop.
     // We save after each improvement.
     loop {
-        train_network(&mut network);
+        train_network(&mut network, &mut optimizer, &mut replay_buffer);
         network.save(&format!("iters/alphazero_{:0>8}.data", i));
+        save_optimizer(&optimizer, &format!("iters/optimizer_{:0>8}.data", i));
+        replay_buffer.save(&format!("iters/replay_{:0>8}.data", i));
         i += 1;
     }
 }
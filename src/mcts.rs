@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{cmp::Reverse, fs, ops::Range, time::{Duration, Instant}};
 
 use onitama_move_gen::gen::Game;
 use rand::{
@@ -7,16 +7,163 @@ use rand::{
 };
 use tensor::*;
 
-use crate::{convert::game_to_input, network::Network, rand_game::random_game};
+use crate::{
+    dirichlet::dirichlet,
+    moves::{self, POLICY_SIZE},
+    network::Network,
+    rand_game::random_game,
+    transposition::TranspositionTable,
+};
 
-const EXPLORATION: f64 = 0.5;
+/// How strongly the search favours moves with a high prior and few visits.
+#[derive(Clone, Copy, Debug)]
+pub enum Exploration {
+    /// Fixed c_puct.
+    Constant(f64),
+    /// c_puct grows with the visits of the parent like in AlphaZero:
+    /// c = ln((1 + N + c_base) / c_base) + c_init
+    LogGrowth { c_base: f64, c_init: f64 },
+}
 
-pub struct Node {
-    pub game: Game,
+/// The value assumed for children that have not been visited yet.
+#[derive(Clone, Copy, Debug)]
+pub enum FirstPlayUrgency {
+    /// The value of the parent minus a reduction.
+    Reduction(f64),
+    /// A fixed value, for example 0 to treat them as draws.
+    Value(f64),
+}
+
+/// Parameters of the tree search.
+#[derive(Clone, Copy, Debug)]
+pub struct SearchConfig {
+    pub exploration: Exploration,
+    pub first_play_urgency: FirstPlayUrgency,
+    /// Leaves evaluated together in one batched pass of the network.
+    pub batch_size: usize,
+}
+
+impl SearchConfig {
+    fn c_puct(&self, parent_visits: u32) -> f64 {
+        match self.exploration {
+            Exploration::Constant(c) => c,
+            Exploration::LogGrowth { c_base, c_init } => {
+                ((1. + parent_visits as f64 + c_base) / c_base).ln() + c_init
+            }
+        }
+    }
+}
+
+/// Outcome of a position with perfect play, for the player to move.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Proof {
+    Win,
+    Loss,
+    Draw,
+}
+
+impl Proof {
+    fn value(self) -> f64 {
+        match self {
+            Proof::Win => 1.,
+            Proof::Loss => -1.,
+            Proof::Draw => 0.,
+        }
+    }
+}
+
+/// Index of a node in the arena.
+type NodeId = usize;
+
+const ROOT: NodeId = 0;
+
+/// Location of the children of a node in the arena.
+/// Siblings are always stored next to each other.
+#[derive(Clone, Copy)]
+struct Children {
+    first: u32,
+    len: u32,
+}
+
+impl Children {
+    fn ids(self) -> Range<NodeId> {
+        self.first as NodeId..(self.first + self.len) as NodeId
+    }
+}
+
+/// Search statistics of a single position.
+#[derive(Clone, Copy)]
+struct Entry {
+    game: Game,
+    /// The move which leads to this position from the parent.
+    move_index: usize,
     policy: f64,
     expected_reward: f64,
     visited_count: u32,
-    children: Option<HashMap<usize, Node>>,
+    /// Rollouts which passed through this node and await evaluation.
+    virtual_loss: u32,
+    children: Option<Children>,
+    /// Set once the outcome of the position is known, which ends its search.
+    proof: Option<Proof>,
+}
+
+impl Entry {
+    fn new(game: Game, move_index: usize, policy: f64) -> Entry {
+        Entry {
+            game,
+            move_index,
+            policy,
+            expected_reward: 0.,
+            visited_count: 0,
+            virtual_loss: 0,
+            children: None,
+            proof: if game.is_loss() {
+                Some(Proof::Loss)
+            } else if game.is_win() {
+                Some(Proof::Win)
+            } else {
+                None
+            },
+        }
+    }
+
+    /// PUCT score of a child from the perspective of this node.
+    /// Rollouts waiting for evaluation count as visits which lost.
+    fn upper_confidence_bound(&self, child: &Entry, config: &SearchConfig) -> f64 {
+        let visits = self.visited_count + self.virtual_loss;
+        let child_visits = child.visited_count + child.virtual_loss;
+        let q = if child_visits == 0 {
+            match config.first_play_urgency {
+                FirstPlayUrgency::Reduction(reduction) => self.expected_reward - reduction,
+                FirstPlayUrgency::Value(value) => value,
+            }
+        } else {
+            // The child's reward is from the opponent's perspective.
+            (-child.expected_reward * child.visited_count as f64 - child.virtual_loss as f64)
+                / child_visits as f64
+        };
+        q + config.c_puct(visits) * child.policy * (visits as f64).sqrt() / (1. + child_visits as f64)
+    }
+}
+
+/// Search tree rooted at `game`.
+/// All nodes live in one arena and refer to each other by index,
+/// the root is always the first one.
+pub struct Node {
+    pub game: Game,
+    arena: Vec<Entry>,
+    /// Evaluations shared between nodes for the same position.
+    table: Option<TranspositionTable>,
+}
+
+/// Where a batched rollout ended up.
+enum Selection {
+    /// A finished game with its eval for the player to move.
+    Terminal(f64),
+    /// A node which needs to be evaluated by the network.
+    Expand,
+    /// A node which is already waiting for evaluation in this batch.
+    Collision,
 }
 
 impl Node {
@@ -24,118 +171,1054 @@ impl Node {
     pub fn from(game: Game) -> Node {
         Node {
             game,
-            expected_reward: 0.,
-            policy: 1.,
-            visited_count: 0,
-            children: None,
+            arena: vec![Entry::new(game, 0, 1.)],
+            table: None,
+        }
+    }
+
+    /// Create a root node which looks up positions in a transposition table
+    /// before evaluating them with the network.
+    pub fn with_table(game: Game, table: TranspositionTable) -> Node {
+        Node {
+            table: Some(table),
+            ..Node::from(game)
         }
     }
 
+    /// Take back the transposition table, for example for the next game.
+    pub fn into_table(self) -> Option<TranspositionTable> {
+        self.table
+    }
+
     /// Create a root node with a random game.
     pub fn random() -> Node {
         Node::from(random_game())
     }
 
+    fn children(&self, id: NodeId) -> &[Entry] {
+        &self.arena[self.arena[id].children.unwrap().ids()]
+    }
+
+    fn children_mut(&mut self, id: NodeId) -> &mut [Entry] {
+        let ids = self.arena[id].children.unwrap().ids();
+        &mut self.arena[ids]
+    }
+
+    /// The outcome of the root, if the search has proven it.
+    pub fn proof(&self) -> Option<Proof> {
+        self.arena[ROOT].proof
+    }
+
+    /// A child which wins for the player to move.
+    fn winning_child(&self, id: NodeId) -> Option<NodeId> {
+        self.arena[id]
+            .children
+            .unwrap()
+            .ids()
+            .filter(|&child| self.arena[child].proof == Some(Proof::Loss))
+            .min_by_key(|&child| self.arena[child].move_index)
+    }
+
+    /// The children worth playing: all but proven losses, unless all lose.
+    fn candidates(&self, id: NodeId) -> Vec<NodeId> {
+        let ids = self.arena[id].children.unwrap().ids();
+        let candidates: Vec<_> = ids
+            .clone()
+            .filter(|&child| self.arena[child].proof != Some(Proof::Win))
+            .collect();
+        if candidates.is_empty() {
+            ids.collect()
+        } else {
+            candidates
+        }
+    }
+
+    /// The child which is played without temperature.
+    /// Ties are broken by the lowest move index.
+    fn most_visited_child(&self, id: NodeId) -> NodeId {
+        self.winning_child(id).unwrap_or_else(|| {
+            self.candidates(id)
+                .into_iter()
+                .max_by_key(|&child| {
+                    let child = &self.arena[child];
+                    (child.visited_count, Reverse(child.move_index))
+                })
+                .unwrap()
+        })
+    }
+
+    /// Follow the most visited children from the root while they have visits.
+    pub fn principal_variation(&self) -> Vec<usize> {
+        let mut variation = Vec::new();
+        let mut id = ROOT;
+        while self.arena[id].children.is_some() {
+            id = self.most_visited_child(id);
+            if self.arena[id].visited_count == 0 {
+                break;
+            }
+            variation.push(self.arena[id].move_index);
+        }
+        variation
+    }
+
     /// Get the improved policy after MCTS.
-    pub fn improved_policy(&self) -> [f64; 625] {
-        let mut policy = [0.; 625];
-        for (&move_index, child) in self.children.as_ref().unwrap() {
-            // policy[move_index] = child.expected_reward;  // TODO compare
+    /// Once a winning move is proven all of the policy goes to it.
+    /// If no child was visited, because the root was proven lost right away,
+    /// the priors of the children are used instead.
+    pub fn improved_policy(&self) -> [f64; POLICY_SIZE] {
+        let mut policy = [0.; POLICY_SIZE];
+        if let Some(child) = self.winning_child(ROOT) {
+            policy[self.arena[child].move_index] = 1.;
+            return policy;
+        }
+        let children = self.children(ROOT);
+        let visited_count: u32 = children.iter().map(|child| child.visited_count).sum();
+        if visited_count == 0 {
+            let total: f64 = children.iter().map(|child| child.policy).sum();
+            for child in children {
+                policy[child.move_index] = if total > 0. {
+                    child.policy / total
+                } else {
+                    1. / children.len() as f64
+                };
+            }
+            return policy;
+        }
+        for child in children {
+            // policy[child.move_index] = child.expected_reward;  // TODO compare
 
             // This ensures that the resulting vector's elements add up to 1.
-            policy[move_index] = child.visited_count as f64 / self.visited_count as f64;
+            policy[child.move_index] = child.visited_count as f64 / visited_count as f64;
         }
         policy
     }
 
-    /// Pick a random action based on policy acquired from MCTS.
-    pub fn pick_move(&self) -> usize {
-        let improved_policy = self.improved_policy();
+    /// Mix Dirichlet noise into the priors of the children to encourage
+    /// exploration. Does nothing if the node has not been expanded yet.
+    pub fn add_exploration_noise(&mut self, alpha: f64, epsilon: f64) {
+        if self.arena[ROOT].children.is_some() {
+            let children = self.children_mut(ROOT);
+            let noise = dirichlet(alpha, children.len());
+            for (child, noise) in children.iter_mut().zip(noise.into_iter()) {
+                child.policy = (1. - epsilon) * child.policy + epsilon * noise;
+            }
+        }
+    }
+
+    /// Pick an action based on the visit counts acquired from MCTS. With a
+    /// temperature of 1 moves are sampled in proportion to their visit counts,
+    /// lower temperatures favour the most visited moves more and a temperature
+    /// of 0 deterministically picks the most visited move. A proven winning
+    /// move is always picked and proven losing moves are avoided. Without any
+    /// visits to sample from the most visited move is picked as well.
+    pub fn pick_move(&self, temperature: f64) -> usize {
+        if temperature == 0. || self.winning_child(ROOT).is_some() {
+            return self.arena[self.most_visited_child(ROOT)].move_index;
+        }
+
+        // Divide by the most visits first so low temperatures don't overflow.
+        let children: Vec<_> = self.candidates(ROOT).into_iter().map(|id| &self.arena[id]).collect();
+        let max_visits = children.iter().map(|child| child.visited_count).max().unwrap();
+        if max_visits == 0 {
+            return self.arena[self.most_visited_child(ROOT)].move_index;
+        }
+        let mut weights = [0.; POLICY_SIZE];
+        for child in children {
+            weights[child.move_index] =
+                (child.visited_count as f64 / max_visits as f64).powf(1. / temperature);
+        }
         let mut rng = thread_rng();
-        let distr = WeightedIndex::new(&improved_policy).unwrap();
+        let distr = WeightedIndex::new(&weights).unwrap();
         distr.sample(&mut rng)
     }
 
     /// Return a child of this Node corresponding to the given action index.
-    pub fn step(self, move_index: usize) -> Node {
-        match self.children {
-            Some(mut children) => children.remove(&move_index).unwrap(),
-            None => {
-                for game in self.game.forward() {
-                    let from = game.my & !game.other;
-                    let to = game.other & !game.my;
-                    if move_index == (from * 25 + to) as usize {
-                        return Node::from(game);
+    /// The subtree of the child and the transposition table are kept,
+    /// the rest of the tree is freed.
+    pub fn step(mut self, move_index: usize) -> Node {
+        let mut node = match self.arena[ROOT].children {
+            Some(children) => {
+                let id = children
+                    .ids()
+                    .find(|&id| self.arena[id].move_index == move_index)
+                    .unwrap();
+                self.subtree(id)
+            }
+            None => Node::from(moves::play(&self.game, move_index).unwrap()),
+        };
+        node.table = self.table.take();
+        node
+    }
+
+    /// Copy the subtree below `id` into a new arena, keeping siblings together.
+    fn subtree(&self, id: NodeId) -> Node {
+        let mut arena = vec![self.arena[id]];
+        // Breadth first, so every node is copied before its children.
+        let mut next = 0;
+        while next < arena.len() {
+            if let Some(children) = arena[next].children {
+                let first = arena.len() as u32;
+                arena.extend_from_slice(&self.arena[children.ids()]);
+                arena[next].children = Some(Children {
+                    first,
+                    len: children.len,
+                });
+            }
+            next += 1;
+        }
+        Node {
+            game: arena[ROOT].game,
+            arena,
+            table: None,
+        }
+    }
+
+    /// Create the children using the policy from the network
+    /// and store the evaluation in the transposition table.
+    fn expand(&mut self, id: NodeId, policy: [f64; POLICY_SIZE], eval: f64) {
+        let first = self.arena.len() as u32;
+        let parent = self.arena[id].game;
+        for game in parent.forward() {
+            let move_index = moves::encode(&parent, &game);
+            self.arena.push(Entry::new(game, move_index, policy[move_index]));
+        }
+        let len = self.arena.len() as u32 - first;
+        self.arena[id].children = Some(Children { first, len });
+
+        if let Some(table) = self.table.as_mut() {
+            let priors = self.arena[first as usize..].iter().map(|child| child.policy).collect();
+            table.insert(&self.arena[id].game, priors, eval);
+        }
+    }
+
+    /// Create the children from the transposition table if the position is in
+    /// it. Returns the eval of the position.
+    fn expand_from_table(&mut self, id: NodeId) -> Option<f64> {
+        let parent = self.arena[id].game;
+        let (priors, eval) = self.table.as_mut()?.get(&parent)?;
+        let first = self.arena.len() as u32;
+        for (game, &prior) in parent.forward().zip(priors.iter()) {
+            self.arena.push(Entry::new(game, moves::encode(&parent, &game), prior));
+        }
+        let len = self.arena.len() as u32 - first;
+        self.arena[id].children = Some(Children { first, len });
+        Some(eval)
+    }
+
+    /// Pick the child with the highest upper confidence bound.
+    fn best_child(&self, id: NodeId, config: &SearchConfig) -> NodeId {
+        let parent = &self.arena[id];
+        let mut max_upper_bound = f64::NEG_INFINITY;
+        let mut best = None;
+        for child_id in parent.children.unwrap().ids() {
+            // Never walk into a proven loss when there is anything else.
+            if self.arena[child_id].proof == Some(Proof::Win) {
+                continue;
+            }
+            let upper_confidence_bound = parent.upper_confidence_bound(&self.arena[child_id], config);
+            if upper_confidence_bound > max_upper_bound {
+                max_upper_bound = upper_confidence_bound;
+                best = Some(child_id);
+            }
+        }
+        best.unwrap()
+    }
+
+    /// Walk down the tree adding virtual loss, recording the nodes in `path`.
+    fn select(&mut self, config: &SearchConfig, path: &mut Vec<NodeId>) -> Selection {
+        let mut id = ROOT;
+        loop {
+            path.push(id);
+            let entry = &mut self.arena[id];
+            entry.virtual_loss += 1;
+            // A root which can win right away is expanded to find the move.
+            let unexpanded_root = id == ROOT && entry.children.is_none() && !entry.game.is_loss();
+            if let (Some(proof), false) = (entry.proof, unexpanded_root) {
+                return Selection::Terminal(proof.value());
+            }
+            if entry.children.is_none() {
+                return if entry.virtual_loss > 1 {
+                    Selection::Collision
+                } else {
+                    Selection::Expand
+                };
+            }
+            id = self.best_child(id, config);
+        }
+    }
+
+    /// Remove the virtual loss of a selection which was not evaluated.
+    fn revert(&mut self, path: &[NodeId]) {
+        for &id in path {
+            self.arena[id].virtual_loss -= 1;
+        }
+    }
+
+    /// Prove a node from its children. Returns whether the node is proven.
+    fn update_proof(&mut self, id: NodeId) -> bool {
+        if self.arena[id].children.is_some() {
+            let children = self.children(id);
+            let proof = if children.iter().any(|child| child.proof == Some(Proof::Loss)) {
+                Some(Proof::Win)
+            } else if children.iter().all(|child| child.proof == Some(Proof::Win)) {
+                Some(Proof::Loss)
+            } else if children.iter().all(|child| child.proof.is_some()) {
+                Some(Proof::Draw)
+            } else {
+                None
+            };
+            self.arena[id].proof = proof;
+        }
+        self.arena[id].proof.is_some()
+    }
+
+    /// Replace virtual loss along the path with the eval of the leaf.
+    /// The leaf eval is from the perspective of the player to move in the leaf,
+    /// the returned eval is from the perspective of the parent of the root.
+    /// Proofs are propagated up for as long as they decide the parent.
+    fn backup(&mut self, path: &[NodeId], leaf_eval: f64) -> f64 {
+        let mut eval = leaf_eval;
+        let mut proving = true;
+        for &id in path.iter().rev() {
+            if proving {
+                proving = self.update_proof(id);
+            }
+            let entry = &mut self.arena[id];
+            entry.virtual_loss -= 1;
+            entry.visited_count += 1;
+            match entry.proof {
+                // A proven node is worth exactly its outcome.
+                Some(proof) => {
+                    eval = proof.value();
+                    entry.expected_reward = eval;
+                }
+                None => {
+                    entry.expected_reward = ((entry.visited_count - 1) as f64 * entry.expected_reward + eval)
+                        / (entry.visited_count as f64)
+                }
+            }
+            if let Some(table) = self.table.as_mut() {
+                table.record(&entry.game, eval);
+            }
+            // Changing player perspective.
+            eval = -eval;
+        }
+        eval
+    }
+
+    /// Gather up to `config.batch_size` leaves using virtual loss and evaluate
+    /// them with a single batched pass of the network. Gathering stops early
+    /// when a leaf is selected twice.
+    pub fn rollout_batch<const C: usize>(&mut self, network: &Network<C>, config: &SearchConfig)
+    where
+        [(); 3 * 3 * C]: ,
+        [(); 5 * 5 * C]: ,
+        [(); 1 * 1 * C]: ,
+    {
+        let mut leaves = Vec::new();
+        for _ in 0..config.batch_size {
+            let mut path = Vec::new();
+            match self.select(config, &mut path) {
+                Selection::Terminal(eval) => {
+                    self.backup(&path, eval);
+                }
+                Selection::Expand => match self.expand_from_table(*path.last().unwrap()) {
+                    Some(eval) => {
+                        self.backup(&path, eval);
                     }
+                    None => leaves.push(path),
+                },
+                Selection::Collision => {
+                    self.revert(&path);
+                    break;
                 }
-                unreachable!()
             }
         }
+
+        let games: Vec<_> = leaves
+            .iter()
+            .map(|path| self.arena[*path.last().unwrap()].game)
+            .collect();
+        let evals = network.evaluate_batch(&games);
+        for (path, (probability_vec, eval)) in leaves.iter().zip(evals.into_iter()) {
+            self.expand(*path.last().unwrap(), probability_vec.get_data(), eval);
+            self.backup(path, eval);
+        }
     }
 
     /// Use neural network to guide Monte Carlo tree search.
-    pub fn rollout(&mut self, network: &Network) -> f64 {
-        self.visited_count += 1;
-
-        // Leaf node.
-        if self.game.is_loss() {
-            return -1.;
-        } else if self.game.is_win() {
-            return 1.;
-        }
-
-        // This is the first time we are visiting this node,
-        // initialize all child states.
-        if self.children.is_none() {
-            // Use the neural network to get initial policy for children
-            // and eval for this board.
-            let (probability_vec, eval) = network.feed_forward(game_to_input(&self.game));
-            let policy = probability_vec.get_data();
-
-            let mut children = HashMap::new();
-            for game in self.game.forward() {
-                let from = game.my & !game.other;
-                let to = game.other & !game.my;
-                let move_index = (from * 25 + to) as usize;
-
-                let node = Node {
-                    game,
-                    policy: policy[move_index],
-                    expected_reward: 0.,
-                    visited_count: 0,
-                    children: None,
-                };
+    /// `expected_reward` is from the perspective of the player to move in a
+    /// node, while the returned eval is from the perspective of the parent.
+    pub fn rollout<const C: usize>(&mut self, network: &Network<C>, config: &SearchConfig) -> f64
+    where
+        [(); 3 * 3 * C]: ,
+        [(); 5 * 5 * C]: ,
+        [(); 1 * 1 * C]: ,
+    {
+        let mut path = Vec::new();
+        match self.select(config, &mut path) {
+            Selection::Terminal(eval) => self.backup(&path, eval),
+            // Nothing else awaits evaluation, so there are no collisions.
+            Selection::Expand | Selection::Collision => {
+                // This is the first time we are visiting this node.
+                // Use the neural network to get initial policy for children
+                // and eval for this board.
+                let leaf = *path.last().unwrap();
+                if let Some(eval) = self.expand_from_table(leaf) {
+                    return self.backup(&path, eval);
+                }
+                let (probability_vec, eval) = network.evaluate(&self.arena[leaf].game);
+                self.expand(leaf, probability_vec.get_data(), eval);
+                self.backup(&path, eval)
+            }
+        }
+    }
+}
 
-                children.insert(move_index, node);
+/// What the search thinks about a move from the root.
+#[derive(Clone, Copy, Debug)]
+pub struct ChildStats {
+    pub move_index: usize,
+    pub prior: f64,
+    pub visits: u32,
+    /// Expected reward for the player making the move, if it was visited.
+    pub q: Option<f64>,
+    pub upper_confidence_bound: f64,
+    /// Proven outcome for the opponent after the move.
+    pub proof: Option<Proof>,
+}
+
+impl Node {
+    /// Visits of the root.
+    pub fn visits(&self) -> u32 {
+        self.arena[ROOT].visited_count
+    }
+
+    /// Expected reward of the root for the player to move.
+    pub fn value(&self) -> f64 {
+        self.arena[ROOT].expected_reward
+    }
+
+    /// Number of nodes in the tree.
+    pub fn size(&self) -> usize {
+        self.arena.len()
+    }
+
+    /// Length of the longest path from the root to a node.
+    pub fn depth(&self) -> usize {
+        // Children are always stored after their parent.
+        let mut depths = vec![0; self.arena.len()];
+        for (id, entry) in self.arena.iter().enumerate() {
+            if let Some(children) = entry.children {
+                for child in children.ids() {
+                    depths[child] = depths[id] + 1;
+                }
             }
+        }
+        depths.into_iter().max().unwrap()
+    }
 
-            self.expected_reward = eval;
-            self.children = Some(children);
-            return -eval;
+    /// Statistics of all moves from the root, most visited first.
+    /// Empty if the root has not been expanded.
+    pub fn child_stats(&self, config: &SearchConfig) -> Vec<ChildStats> {
+        let root = &self.arena[ROOT];
+        let children = match root.children {
+            Some(_) => self.children(ROOT),
+            None => &[],
+        };
+        let mut stats: Vec<_> = children
+            .iter()
+            .map(|child| ChildStats {
+                move_index: child.move_index,
+                prior: child.policy,
+                visits: child.visited_count,
+                q: (child.visited_count > 0).then(|| -child.expected_reward),
+                upper_confidence_bound: root.upper_confidence_bound(child, config),
+                proof: child.proof,
+            })
+            .collect();
+        stats.sort_by_key(|child| (Reverse(child.visits), child.move_index));
+        stats
+    }
+
+    /// Table of the root, its moves and the principal variation for debugging.
+    pub fn debug_table(&self, config: &SearchConfig) -> String {
+        let mut table = format!(
+            "visits {}, value {:.4}, proof {:?}, size {}, depth {}\n",
+            self.visits(),
+            self.value(),
+            self.proof(),
+            self.size(),
+            self.depth()
+        );
+        table += &format!(
+            "{:>5} {:>7} {:>7} {:>7} {:>7} {:>5}\n",
+            "move", "prior", "visits", "q", "ucb", "proof"
+        );
+        for child in self.child_stats(config) {
+            let q = child.q.map_or("-".to_string(), |q| format!("{:.4}", q));
+            let proof = child.proof.map_or("-".to_string(), |proof| format!("{:?}", proof));
+            table += &format!(
+                "{:>5} {:>7.4} {:>7} {:>7} {:>7.4} {:>5}\n",
+                moves::to_notation(child.move_index),
+                child.prior,
+                child.visits,
+                q,
+                child.upper_confidence_bound,
+                proof
+            );
         }
+        let variation: Vec<_> = self
+            .principal_variation()
+            .into_iter()
+            .map(moves::to_notation)
+            .collect();
+        table += &format!("pv: {}\n", variation.join(" "));
+        table
+    }
 
-        // We have been at this node before.
-        // Pick which node to rollout.
-        let mut children = self.children.take().unwrap();
-        let mut max_upper_bound = f64::NEG_INFINITY;
-        let mut next_node = None;
-        for (_move_index, node) in children.iter_mut() {
-            let upper_confidence_bound = node.expected_reward
-                + EXPLORATION * node.policy * (self.visited_count as f64).sqrt()
-                    / (1.0 + node.visited_count as f64);
-            if upper_confidence_bound > max_upper_bound {
-                max_upper_bound = upper_confidence_bound;
-                next_node = Some(node);
+    /// Print `debug_table`.
+    pub fn dump(&self, config: &SearchConfig) {
+        print!("{}", self.debug_table(config));
+    }
+
+    /// Graphviz DOT graph of the tree, leaving out nodes deeper than
+    /// `max_depth` or with fewer than `min_visits` visits. Every node is
+    /// labelled with the move leading to it, N, Q for the player making the
+    /// move and P.
+    pub fn to_dot(&self, max_depth: usize, min_visits: u32) -> String {
+        let root = &self.arena[ROOT];
+        let mut dot = String::from("digraph mcts {\n    node [shape=box];\n");
+        dot += &format!(
+            "    n{} [label=\"root\\nN={}\\nV={:.3}\"];\n",
+            ROOT, root.visited_count, root.expected_reward
+        );
+        let mut stack = vec![(ROOT, 0)];
+        while let Some((id, depth)) = stack.pop() {
+            let children = match self.arena[id].children {
+                Some(children) if depth < max_depth => children,
+                _ => continue,
+            };
+            for child_id in children.ids() {
+                let child = &self.arena[child_id];
+                if child.visited_count < min_visits {
+                    continue;
+                }
+                let proof = child.proof.map_or(String::new(), |proof| format!("\\n{:?}", proof));
+                dot += &format!(
+                    "    n{} [label=\"{}\\nN={}\\nQ={:.3}\\nP={:.3}{}\"];\n",
+                    child_id,
+                    moves::to_notation(child.move_index),
+                    child.visited_count,
+                    -child.expected_reward,
+                    child.policy,
+                    proof
+                );
+                dot += &format!("    n{} -> n{};\n", id, child_id);
+                stack.push((child_id, depth + 1));
+            }
+        }
+        dot += "}\n";
+        dot
+    }
+
+    /// Write `to_dot` to a file.
+    pub fn save_dot(&self, path: &str, max_depth: usize, min_visits: u32) {
+        fs::write(path, self.to_dot(max_depth, min_visits)).expect("couldn't save DOT file");
+    }
+}
+
+/// How much effort to spend on a search.
+#[derive(Clone, Copy, Debug)]
+pub struct Budget {
+    /// Rollouts to do at most.
+    pub rollouts: u32,
+    /// Stop once this much time has passed.
+    pub time: Option<Duration>,
+    /// Stop once the remaining rollouts can't change the most visited move.
+    pub stop_early: bool,
+}
+
+/// What a search found out about the root.
+pub struct SearchResult {
+    pub best_move: usize,
+    /// Visit counts of the moves divided by the total.
+    pub policy: [f64; POLICY_SIZE],
+    /// Expected reward of the root for the player to move.
+    pub value: f64,
+    pub principal_variation: Vec<usize>,
+    /// Rollouts done by this search.
+    pub rollouts: u32,
+    pub nodes_per_second: f64,
+}
+
+impl Node {
+    /// Whether the most visited move stays the most visited
+    /// whatever happens in `remaining` more rollouts.
+    fn is_decided(&self, remaining: u32) -> bool {
+        let mut visits: Vec<_> = self
+            .candidates(ROOT)
+            .into_iter()
+            .map(|id| self.arena[id].visited_count)
+            .collect();
+        visits.sort_unstable_by_key(|&visits| Reverse(visits));
+        match visits[..] {
+            [best, second, ..] => best - second > remaining,
+            _ => true,
+        }
+    }
+}
+
+/// Search from the root of `node` until the budget runs out or the root is
+/// proven. The game must not be over yet.
+pub fn search<const C: usize>(
+    node: &mut Node,
+    network: &Network<C>,
+    config: &SearchConfig,
+    budget: Budget,
+) -> SearchResult
+where
+    [(); 3 * 3 * C]: ,
+    [(); 5 * 5 * C]: ,
+    [(); 1 * 1 * C]: ,
+{
+    assert!(!node.game.is_loss());
+    let start = Instant::now();
+    let initial_visits = node.arena[ROOT].visited_count;
+    loop {
+        let rollouts = node.arena[ROOT].visited_count - initial_visits;
+        let remaining = budget.rollouts.saturating_sub(rollouts);
+        // The root has to be expanded before there is any move to pick.
+        if node.arena[ROOT].children.is_some()
+            && (remaining == 0
+                || node.proof().is_some()
+                || budget.time.map_or(false, |time| start.elapsed() >= time)
+                || budget.stop_early && node.is_decided(remaining))
+        {
+            break;
+        }
+        // Don't go over the budget with the last batch.
+        let config = SearchConfig {
+            batch_size: config.batch_size.min(remaining.max(1) as usize),
+            ..*config
+        };
+        node.rollout_batch(network, &config);
+    }
+
+    let rollouts = node.arena[ROOT].visited_count - initial_visits;
+    SearchResult {
+        best_move: node.pick_move(0.),
+        policy: node.improved_policy(),
+        value: node.arena[ROOT].expected_reward,
+        principal_variation: node.principal_variation(),
+        rollouts,
+        nodes_per_second: rollouts as f64 / start.elapsed().as_secs_f64(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::seq::SliceRandom;
+
+    use super::*;
+
+    const CONFIG: SearchConfig = SearchConfig {
+        exploration: Exploration::Constant(1.),
+        first_play_urgency: FirstPlayUrgency::Reduction(0.25),
+        batch_size: 8,
+    };
+
+    /// Every expanded node was evaluated once and then visited through its
+    /// children, until it was proven.
+    fn assert_visits_add_up(node: &Node) {
+        for (id, entry) in node.arena.iter().enumerate() {
+            if entry.children.is_some() && entry.proof.is_none() {
+                let child_visits: u32 = node.children(id).iter().map(|child| child.visited_count).sum();
+                assert_eq!(entry.visited_count, child_visits + 1);
+            }
+            assert_eq!(entry.virtual_loss, 0);
+        }
+    }
+
+    #[test]
+    fn rollouts_keep_visits_consistent() {
+        with_larger_stack(|| {
+            let network = Network::<4>::init(1);
+            let mut node = Node::random();
+            for _ in 0..32 {
+                node.rollout(&network, &CONFIG);
+            }
+            for _ in 0..8 {
+                node.rollout_batch(&network, &CONFIG);
+            }
+            assert_visits_add_up(&node);
+        })
+    }
+
+    #[test]
+    fn step_reuses_subtree() {
+        with_larger_stack(|| {
+            let network = Network::<4>::init(1);
+            let mut node = Node::random();
+            for _ in 0..64 {
+                node.rollout(&network, &CONFIG);
+            }
+            let move_index = node.pick_move(0.);
+            let child = *node.children(ROOT).iter().find(|child| child.move_index == move_index).unwrap();
+            let node = node.step(move_index);
+            assert_eq!(node.game.my, child.game.my);
+            assert_eq!(node.game.other, child.game.other);
+            assert_eq!(node.arena[ROOT].visited_count, child.visited_count);
+            assert_eq!(node.arena[ROOT].expected_reward, child.expected_reward);
+            assert_visits_add_up(&node);
+            // Only the subtree was copied, and all of it.
+            let expanded: u32 = node.arena.iter().filter_map(|entry| entry.children).map(|c| c.len).sum();
+            assert_eq!(node.arena.len() as u32, expanded + 1);
+        })
+    }
+
+    #[test]
+    fn table_shares_evaluations() {
+        with_larger_stack(|| {
+            let game = random_game();
+            let mut node = Node::with_table(game, TranspositionTable::new(1024, false));
+            node.rollout(&Network::<4>::init(1), &CONFIG);
+            let priors: Vec<_> = node.children(ROOT).iter().map(|child| child.policy).collect();
+            // A different network is not asked about positions in the table.
+            let mut node = Node::with_table(game, node.into_table().unwrap());
+            node.rollout(&Network::<4>::init(1), &CONFIG);
+            let shared: Vec<_> = node.children(ROOT).iter().map(|child| child.policy).collect();
+            assert_eq!(priors, shared);
+        })
+    }
+
+    #[test]
+    fn search_budget() {
+        with_larger_stack(|| {
+            let network = Network::<4>::init(1);
+            let budget = Budget {
+                rollouts: 64,
+                time: None,
+                stop_early: false,
+            };
+            let mut node = Node::random();
+            let result = search(&mut node, &network, &CONFIG, budget);
+            // Batches may stop early, but never go past the budget.
+            assert_eq!(result.rollouts, 64);
+            assert_eq!(result.best_move, node.pick_move(0.));
+            assert_eq!(result.principal_variation.first(), Some(&result.best_move));
+            assert!((result.policy.iter().sum::<f64>() - 1.).abs() < 1e-9);
+
+            let budget = Budget {
+                stop_early: true,
+                ..budget
+            };
+            let mut node = Node::random();
+            let result = search(&mut node, &network, &CONFIG, budget);
+            assert!(result.rollouts <= 64);
+            assert!(result.rollouts == 64 || node.is_decided(64 - result.rollouts));
+        })
+    }
+
+    #[test]
+    fn stats_of_the_tree() {
+        with_larger_stack(|| {
+            let network = Network::<4>::init(1);
+            let mut node = Node::random();
+            assert!(node.child_stats(&CONFIG).is_empty());
+            assert_eq!((node.size(), node.depth()), (1, 0));
+            for _ in 0..32 {
+                node.rollout(&network, &CONFIG);
+            }
+            let stats = node.child_stats(&CONFIG);
+            assert_eq!(stats.iter().map(|child| child.visits).sum::<u32>() + 1, node.visits());
+            assert!(stats.windows(2).all(|pair| pair[0].visits >= pair[1].visits));
+            assert_eq!(node.principal_variation().first(), Some(&stats[0].move_index));
+            assert!(node.depth() >= node.principal_variation().len());
+            // Headers, one line per move and the principal variation.
+            assert_eq!(node.debug_table(&CONFIG).lines().count(), stats.len() + 3);
+        })
+    }
+
+    #[test]
+    fn dot_export() {
+        with_larger_stack(|| {
+            let network = Network::<4>::init(1);
+            let mut node = Node::random();
+            for _ in 0..32 {
+                node.rollout(&network, &CONFIG);
+            }
+            let dot = node.to_dot(usize::MAX, 1);
+            assert!(dot.starts_with("digraph"));
+            let visited = node.arena.iter().filter(|entry| entry.visited_count > 0).count();
+            assert_eq!(dot.matches(" -> ").count(), visited - 1);
+            // Only the moves from the root.
+            let visited_moves = node.child_stats(&CONFIG).iter().filter(|child| child.visits > 0).count();
+            assert_eq!(node.to_dot(1, 1).matches(" -> ").count(), visited_moves);
+            assert_eq!(node.to_dot(usize::MAX, node.visits() + 1).matches(" -> ").count(), 0);
+        })
+    }
+
+    /// Whether the player to move can force a win within `plies`.
+    fn wins_within(game: &Game, plies: u32) -> bool {
+        plies > 0 && !game.is_loss() && game.forward().any(|next| loses_within(&next, plies - 1))
+    }
+
+    /// Whether the player to move loses within `plies` whatever they play.
+    fn loses_within(game: &Game, plies: u32) -> bool {
+        game.is_loss() || (plies > 0 && game.forward().all(|next| wins_within(&next, plies - 1)))
+    }
+
+    /// Play random games until reaching a position which satisfies `predicate`.
+    fn find_position(predicate: impl Fn(&Game) -> bool) -> Game {
+        let mut rng = thread_rng();
+        loop {
+            let mut game = random_game();
+            while !game.is_loss() {
+                if predicate(&game) {
+                    return game;
+                }
+                let moves: Vec<_> = game.forward().collect();
+                game = *moves.choose(&mut rng).unwrap();
             }
         }
-        // Rollout next node.
-        let eval = next_node.unwrap().rollout(&network);
-        self.children = Some(children);
+    }
 
-        // Take the mean of the expected reward and eval.
-        self.expected_reward =
-            ((self.visited_count - 1) as f64 * self.expected_reward + eval) / (self.visited_count as f64);
+    /// Search until the root is proven, giving up after `rollouts`.
+    fn solve(game: Game, rollouts: u32) -> Node {
+        let network = Network::<4>::init(1);
+        let mut node = Node::from(game);
+        for _ in 0..rollouts / 8 {
+            if node.proof().is_some() {
+                break;
+            }
+            node.rollout_batch(&network, &CONFIG);
+        }
+        node
+    }
+
+    #[test]
+    fn proves_win_in_one() {
+        with_larger_stack(|| {
+            // Capturing the king or reaching the temple.
+            let game = find_position(|game| wins_within(game, 1));
+            let node = solve(game, 8);
+            assert_eq!(node.proof(), Some(Proof::Win));
+            // Even when sampling the winning move is picked.
+            let move_index = node.pick_move(1.);
+            assert!(node.step(move_index).game.is_loss());
+        })
+    }
+
+    #[test]
+    fn forced_loss_in_one() {
+        with_larger_stack(|| {
+            // Every move lets the opponent win right away, so the root is
+            // proven lost before any child is visited.
+            let game = find_position(|game| game.forward().all(|next| next.is_win()));
+            let node = solve(game, 8);
+            assert_eq!(node.proof(), Some(Proof::Loss));
+            let policy = node.improved_policy();
+            assert!(policy.iter().all(|p| p.is_finite()));
+            assert!((policy.iter().sum::<f64>() - 1.).abs() < 1e-9);
+            for &temperature in [0., 1.].iter() {
+                let move_index = node.pick_move(temperature);
+                assert!(node.children(ROOT).iter().any(|child| child.move_index == move_index));
+            }
+        })
+    }
+
+    #[test]
+    fn proves_win_in_two() {
+        with_larger_stack(|| {
+            let game = find_position(|game| !wins_within(game, 1) && wins_within(game, 3));
+            let node = solve(game, 10_000);
+            assert_eq!(node.proof(), Some(Proof::Win));
+            let move_index = node.pick_move(1.);
+            assert_eq!(node.improved_policy()[move_index], 1.);
+            assert_eq!(node.step(move_index).proof(), Some(Proof::Loss));
+        })
+    }
+}
+
+#[cfg(test)]
+mod benches {
+    use std::collections::HashMap;
+
+    use test::Bencher;
+
+    use super::*;
+    use crate::alpha_zero::{BLOCKS, CHANNELS};
 
-        -eval
+    const CONFIG: SearchConfig = SearchConfig {
+        exploration: Exploration::Constant(1.),
+        first_play_urgency: FirstPlayUrgency::Reduction(0.25),
+        batch_size: 8,
+    };
+
+    /// The tree as it was before the arena, with a `HashMap` of children.
+    struct HashMapNode {
+        game: Game,
+        policy: f64,
+        expected_reward: f64,
+        visited_count: u32,
+        children: Option<HashMap<usize, HashMapNode>>,
+    }
+
+    impl HashMapNode {
+        fn from(game: Game, policy: f64) -> HashMapNode {
+            HashMapNode {
+                game,
+                policy,
+                expected_reward: 0.,
+                visited_count: 0,
+                children: None,
+            }
+        }
+
+        fn pick_move(&self) -> usize {
+            let children = self.children.as_ref().unwrap();
+            *children.iter().max_by_key(|(_, child)| child.visited_count).unwrap().0
+        }
+
+        fn step(self, move_index: usize) -> HashMapNode {
+            self.children.unwrap().remove(&move_index).unwrap()
+        }
+
+        fn rollout(&mut self, network: &Network<CHANNELS>, config: &SearchConfig) -> f64 {
+            self.visited_count += 1;
+            if self.game.is_loss() {
+                self.expected_reward = -1.;
+                return 1.;
+            } else if self.game.is_win() {
+                self.expected_reward = 1.;
+                return -1.;
+            }
+
+            if self.children.is_none() {
+                let (probability_vec, eval) = network.evaluate(&self.game);
+                let policy = probability_vec.get_data();
+                let mut children = HashMap::new();
+                for game in self.game.forward() {
+                    let move_index = moves::encode(&self.game, &game);
+                    children.insert(move_index, HashMapNode::from(game, policy[move_index]));
+                }
+                self.children = Some(children);
+                self.expected_reward = eval;
+                return -eval;
+            }
+
+            let c = config.c_puct(self.visited_count);
+            let sqrt_visits = (self.visited_count as f64).sqrt();
+            let parent_reward = self.expected_reward;
+            let next_node = self
+                .children
+                .as_mut()
+                .unwrap()
+                .values_mut()
+                .max_by(|a, b| {
+                    let ucb = |child: &HashMapNode| {
+                        let q = if child.visited_count == 0 {
+                            match config.first_play_urgency {
+                                FirstPlayUrgency::Reduction(reduction) => parent_reward - reduction,
+                                FirstPlayUrgency::Value(value) => value,
+                            }
+                        } else {
+                            -child.expected_reward
+                        };
+                        q + c * child.policy * sqrt_visits / (1. + child.visited_count as f64)
+                    };
+                    ucb(a).partial_cmp(&ucb(b)).unwrap()
+                })
+                .unwrap();
+            let eval = next_node.rollout(network, config);
+            self.expected_reward =
+                ((self.visited_count - 1) as f64 * self.expected_reward + eval) / (self.visited_count as f64);
+            -eval
+        }
+    }
+
+    #[bench]
+    fn rollouts(ben: &mut Bencher) {
+        with_larger_stack(move || {
+            let network = Network::<CHANNELS>::init(BLOCKS);
+            let game = random_game();
+            ben.iter(|| {
+                let mut node = Node::from(game);
+                for _ in 0..64 {
+                    node.rollout(&network, &CONFIG);
+                }
+            });
+        })
+    }
+
+    #[bench]
+    fn hash_map_rollouts(ben: &mut Bencher) {
+        with_larger_stack(move || {
+            let network = Network::<CHANNELS>::init(BLOCKS);
+            let game = random_game();
+            ben.iter(|| {
+                let mut node = HashMapNode::from(game, 1.);
+                for _ in 0..64 {
+                    node.rollout(&network, &CONFIG);
+                }
+            });
+        })
+    }
+
+    #[bench]
+    fn play_moves(ben: &mut Bencher) {
+        with_larger_stack(move || {
+            let network = Network::<CHANNELS>::init(BLOCKS);
+            let game = random_game();
+            ben.iter(|| {
+                let mut node = Node::from(game);
+                for _ in 0..4 {
+                    if node.game.is_loss() {
+                        break;
+                    }
+                    for _ in 0..32 {
+                        node.rollout(&network, &CONFIG);
+                    }
+                    let move_index = node.pick_move(0.);
+                    node = node.step(move_index);
+                }
+            });
+        })
+    }
+
+    #[bench]
+    fn hash_map_play_moves(ben: &mut Bencher) {
+        with_larger_stack(move || {
+            let network = Network::<CHANNELS>::init(BLOCKS);
+            let game = random_game();
+            ben.iter(|| {
+                let mut node = HashMapNode::from(game, 1.);
+                for _ in 0..4 {
+                    if node.game.is_loss() {
+                        break;
+                    }
+                    for _ in 0..32 {
+                        node.rollout(&network, &CONFIG);
+                    }
+                    let move_index = node.pick_move();
+                    node = node.step(move_index);
+                }
+            });
+        })
+    }
+
+    #[bench]
+    fn batched_rollouts(ben: &mut Bencher) {
+        with_larger_stack(move || {
+            let network = Network::<CHANNELS>::init(BLOCKS);
+            let game = random_game();
+            ben.iter(|| {
+                let mut node = Node::from(game);
+                for _ in 0..8 {
+                    node.rollout_batch(&network, &CONFIG);
+                }
+            });
+        })
     }
 }
@@ -0,0 +1,259 @@
+use std::{error::Error, fmt, io};
+
+const MAGIC: &[u8; 8] = b"ONITAMAZ";
+/// Version of the format, to be bumped whenever the body changes.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Hyperparameters which decide the shapes of the tensors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Architecture {
+    pub channels: usize,
+    pub blocks: usize,
+    pub policy_size: usize,
+    pub value_hidden: usize,
+}
+
+/// A parameter tensor, with its values in the order of the Rust types.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NamedTensor {
+    pub name: String,
+    pub shape: Vec<usize>,
+    pub data: Vec<f64>,
+}
+
+/// Why a network could not be loaded.
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    /// Neither this format nor one of the older ones.
+    NotAModel,
+    /// Written by a newer version of the format.
+    UnsupportedVersion(u32),
+    /// Written before the residual tower, whose weights do not fit any more.
+    /// Such files can be converted with `network::convert_legacy`.
+    Obsolete,
+    ChecksumMismatch { stored: u32, computed: u32 },
+    /// The body or an older file could not be decoded.
+    Malformed(String),
+    /// The file is for a network with a different shape.
+    Architecture {
+        field: &'static str,
+        saved: usize,
+        expected: usize,
+    },
+    /// Tensors are missing, unknown or out of order.
+    TensorMismatch { expected: String, found: String },
+    ShapeMismatch {
+        name: String,
+        saved: Vec<usize>,
+        expected: Vec<usize>,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(err) => write!(f, "couldn't read file: {}", err),
+            LoadError::NotAModel => write!(f, "not a network file"),
+            LoadError::UnsupportedVersion(version) => write!(
+                f,
+                "format version {} is newer than the supported version {}",
+                version, FORMAT_VERSION
+            ),
+            LoadError::Obsolete => write!(
+                f,
+                "network was saved before the residual tower, its weights do not fit the current network"
+            ),
+            LoadError::ChecksumMismatch { stored, computed } => write!(
+                f,
+                "checksum mismatch, the file is corrupted (stored {:08x}, computed {:08x})",
+                stored, computed
+            ),
+            LoadError::Malformed(reason) => write!(f, "malformed network file: {}", reason),
+            LoadError::Architecture {
+                field,
+                saved,
+                expected,
+            } => write!(
+                f,
+                "network was saved with {} {}, but {} is expected",
+                field, saved, expected
+            ),
+            LoadError::TensorMismatch { expected, found } => {
+                write!(f, "expected tensor {}, found {}", expected, found)
+            }
+            LoadError::ShapeMismatch {
+                name,
+                saved,
+                expected,
+            } => write!(
+                f,
+                "tensor {} was saved with shape {:?}, but {:?} is expected",
+                name, saved, expected
+            ),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> LoadError {
+        LoadError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for LoadError {
+    fn from(err: bincode::Error) -> LoadError {
+        LoadError::Malformed(err.to_string())
+    }
+}
+
+type Body = ((usize, usize, usize, usize), Vec<(String, Vec<usize>, Vec<f64>)>);
+
+/// Whether `data` starts with the magic number of this format.
+/// Files without it may be in one of the older formats.
+pub fn has_magic(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Write a network file: the magic number, the format version, a bincode body
+/// with the architecture and the named tensors, and a CRC-32 of everything
+/// before it. The version and checksum are little-endian.
+pub fn encode(architecture: &Architecture, tensors: &[NamedTensor]) -> Vec<u8> {
+    let Architecture {
+        channels,
+        blocks,
+        policy_size,
+        value_hidden,
+    } = *architecture;
+    let tensors: Vec<_> = tensors
+        .iter()
+        .map(|tensor| (&tensor.name, &tensor.shape, &tensor.data))
+        .collect();
+    let mut data = MAGIC.to_vec();
+    data.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    data.extend(bincode::serialize(&((channels, blocks, policy_size, value_hidden), tensors)).unwrap());
+    data.extend_from_slice(&crc32(&data).to_le_bytes());
+    data
+}
+
+/// Check the magic number, version and checksum and decode the body.
+/// Every tensor is checked to have as many values as its shape says.
+pub fn decode(data: &[u8]) -> Result<(Architecture, Vec<NamedTensor>), LoadError> {
+    if !has_magic(data) {
+        return Err(LoadError::NotAModel);
+    }
+    if data.len() < MAGIC.len() + 8 {
+        return Err(LoadError::Malformed("file is truncated".to_string()));
+    }
+    let (contents, checksum) = data.split_at(data.len() - 4);
+    let (header, body) = contents.split_at(MAGIC.len() + 4);
+    let version = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+    if version > FORMAT_VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+    let stored = u32::from_le_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]);
+    let computed = crc32(contents);
+    if stored != computed {
+        return Err(LoadError::ChecksumMismatch { stored, computed });
+    }
+
+    let ((channels, blocks, policy_size, value_hidden), tensors): Body = bincode::deserialize(body)?;
+    let architecture = Architecture {
+        channels,
+        blocks,
+        policy_size,
+        value_hidden,
+    };
+    let tensors = tensors
+        .into_iter()
+        .map(|(name, shape, data)| {
+            if shape.iter().product::<usize>() != data.len() {
+                return Err(LoadError::Malformed(format!(
+                    "tensor {} has {} values for shape {:?}",
+                    name,
+                    data.len(),
+                    shape
+                )));
+            }
+            Ok(NamedTensor { name, shape, data })
+        })
+        .collect::<Result<_, _>>()?;
+    Ok((architecture, tensors))
+}
+
+/// CRC-32 as used by zip and PNG.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example() -> (Architecture, Vec<NamedTensor>) {
+        let architecture = Architecture {
+            channels: 2,
+            blocks: 1,
+            policy_size: 625,
+            value_hidden: 4,
+        };
+        let tensors = vec![
+            NamedTensor {
+                name: "a".to_string(),
+                shape: vec![2, 3],
+                data: vec![1., 2., 3., 4., 5., 6.],
+            },
+            NamedTensor {
+                name: "b".to_string(),
+                shape: vec![1],
+                data: vec![-1.],
+            },
+        ];
+        (architecture, tensors)
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn encode_then_decode() {
+        let (architecture, tensors) = example();
+        let data = encode(&architecture, &tensors);
+        assert!(has_magic(&data));
+        assert_eq!(decode(&data).unwrap(), (architecture, tensors));
+    }
+
+    #[test]
+    fn detect_corruption() {
+        let (architecture, tensors) = example();
+        let mut data = encode(&architecture, &tensors);
+        let last_value = data.len() - 5;
+        data[last_value] ^= 1;
+        assert!(matches!(decode(&data), Err(LoadError::ChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn reject_newer_versions() {
+        let (architecture, tensors) = example();
+        let mut data = encode(&architecture, &tensors);
+        data[8..12].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(matches!(decode(&data), Err(LoadError::UnsupportedVersion(_))));
+    }
+
+    #[test]
+    fn reject_other_files() {
+        assert!(matches!(decode(b"not a network"), Err(LoadError::NotAModel)));
+        assert!(matches!(decode(MAGIC), Err(LoadError::Malformed(_))));
+    }
+}
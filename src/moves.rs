@@ -0,0 +1,230 @@
+use onitama_move_gen::gen::{Game, PIECE_MASK};
+
+/// How moves are numbered in the policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ActionSpace {
+    /// `from * 25 + to`. When both cards in hand allow the same move they
+    /// share an index.
+    Squares,
+    /// `card * 625 + from * 25 + to`, where `card` is the slot in hand of the
+    /// card which was used, 0 for the card with the lower index.
+    CardSquares,
+}
+
+/// Action space of the network and the search.
+pub const ACTION_SPACE: ActionSpace = ActionSpace::Squares;
+/// Number of policy outputs.
+pub const POLICY_SIZE: usize = ACTION_SPACE.size();
+
+impl ActionSpace {
+    pub const fn size(self) -> usize {
+        match self {
+            ActionSpace::Squares => 625,
+            ActionSpace::CardSquares => 2 * 625,
+        }
+    }
+
+    /// Index of the move from `prev` to `next` in this action space.
+    pub fn encode(self, prev: &Game, next: &Game) -> usize {
+        let squares = encode_squares(prev, next);
+        match self {
+            ActionSpace::Squares => squares,
+            ActionSpace::CardSquares => card_slot(prev, next) * 625 + squares,
+        }
+    }
+
+    /// Slot of the card and squares which the piece moves from and to.
+    pub fn decode(self, move_index: usize) -> (usize, u32, u32) {
+        assert!(move_index < self.size());
+        let squares = move_index % 625;
+        (move_index / 625, (squares / 25) as u32, (squares % 25) as u32)
+    }
+
+    /// Find the position after the move with the given index. When two cards
+    /// allow the same move in `Squares` the first one `Game::forward` gives is
+    /// used.
+    pub fn play(self, game: &Game, move_index: usize) -> Option<Game> {
+        game.forward().find(|next| self.encode(game, next) == move_index)
+    }
+
+    /// Algebraic-style notation like `a1b2`, with files `a` to `e` and ranks
+    /// `1` to `5` counted from the player making the move. In `CardSquares`
+    /// the slot of the card follows, like `a1b2/1`.
+    pub fn to_notation(self, move_index: usize) -> String {
+        let (card, from, to) = self.decode(move_index);
+        let squares = square_to_notation(from) + &square_to_notation(to);
+        match self {
+            ActionSpace::Squares => squares,
+            ActionSpace::CardSquares => format!("{}/{}", squares, card),
+        }
+    }
+
+    /// Parse notation from `to_notation` back into a move index.
+    pub fn from_notation(self, notation: &str) -> Option<usize> {
+        let mut parts = notation.splitn(2, '/');
+        let squares = parts.next()?.as_bytes();
+        let card = match (self, parts.next()) {
+            (ActionSpace::Squares, None) => 0,
+            (ActionSpace::CardSquares, Some("0")) => 0,
+            (ActionSpace::CardSquares, Some("1")) => 1,
+            _ => return None,
+        };
+        match squares {
+            &[from_file, from_rank, to_file, to_rank] => {
+                let from = square_from_notation(from_file, from_rank)?;
+                let to = square_from_notation(to_file, to_rank)?;
+                Some(card * 625 + (from * 25 + to) as usize)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Index of the move from `prev` to `next` in the policy.
+/// Squares are seen from the player making the move, who is the player to move
+/// in `prev` and the other player in `next`. Passing, when no piece can move,
+/// is encoded as moving the king to its own square.
+pub fn encode(prev: &Game, next: &Game) -> usize {
+    ACTION_SPACE.encode(prev, next)
+}
+
+/// Slot of the card and squares which the piece moves from and to.
+pub fn decode(move_index: usize) -> (usize, u32, u32) {
+    ACTION_SPACE.decode(move_index)
+}
+
+/// Find the position after the move with the given index.
+pub fn play(game: &Game, move_index: usize) -> Option<Game> {
+    ACTION_SPACE.play(game, move_index)
+}
+
+/// Notation of a move, see `ActionSpace::to_notation`.
+pub fn to_notation(move_index: usize) -> String {
+    ACTION_SPACE.to_notation(move_index)
+}
+
+/// Parse notation from `to_notation` back into a move index.
+pub fn from_notation(notation: &str) -> Option<usize> {
+    ACTION_SPACE.from_notation(notation)
+}
+
+/// Which moves in the policy are legal in `game`.
+pub fn legal_mask(game: &Game) -> [bool; POLICY_SIZE] {
+    let mut mask = [false; POLICY_SIZE];
+    for next in game.forward() {
+        mask[encode(game, &next)] = true;
+    }
+    mask
+}
+
+/// `from * 25 + to`, ignoring the card.
+fn encode_squares(prev: &Game, next: &Game) -> usize {
+    let before = prev.my & PIECE_MASK;
+    let after = next.other & PIECE_MASK;
+    if before == after {
+        let king = prev.my >> 25;
+        return (king * 25 + king) as usize;
+    }
+    let from = (before & !after).trailing_zeros();
+    let to = (after & !before).trailing_zeros();
+    (from * 25 + to) as usize
+}
+
+/// Slot in hand of the card used for the move. The mover's hand is in the low
+/// bits of `prev.cards` and in the high bits of `next.cards`.
+fn card_slot(prev: &Game, next: &Game) -> usize {
+    let hand = prev.cards & 0xFFFF;
+    let used = hand & !(next.cards >> 16);
+    debug_assert_eq!(used.count_ones(), 1);
+    (hand & (used - 1) != 0) as usize
+}
+
+fn square_to_notation(square: u32) -> String {
+    let file = (b'a' + (square % 5) as u8) as char;
+    let rank = (b'1' + (square / 5) as u8) as char;
+    format!("{}{}", file, rank)
+}
+
+fn square_from_notation(file: u8, rank: u8) -> Option<u32> {
+    match (file, rank) {
+        (b'a'..=b'e', b'1'..=b'5') => Some((rank - b'1') as u32 * 5 + (file - b'a') as u32),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{seq::SliceRandom, thread_rng};
+
+    use super::*;
+    use crate::rand_game::random_game;
+
+    const SPACES: [ActionSpace; 2] = [ActionSpace::Squares, ActionSpace::CardSquares];
+
+    #[test]
+    fn forward_moves_round_trip() {
+        let mut rng = thread_rng();
+        for _ in 0..100 {
+            let mut game = random_game();
+            while !game.is_loss() {
+                let moves: Vec<_> = game.forward().collect();
+                for &space in SPACES.iter() {
+                    for next in moves.iter() {
+                        let move_index = space.encode(&game, next);
+                        assert!(move_index < space.size());
+                        let (_, from, to) = space.decode(move_index);
+                        // The piece belongs to the player making the move,
+                        // unless they pass.
+                        if from != to {
+                            assert_ne!(game.my & 1 << from, 0);
+                            assert_eq!(next.other & 1 << from, 0);
+                            assert_ne!(next.other & 1 << to, 0);
+                        }
+                        assert_eq!(space.from_notation(&space.to_notation(move_index)), Some(move_index));
+                        let played = space.play(&game, move_index).unwrap();
+                        assert_eq!(space.encode(&game, &played), move_index);
+                    }
+                }
+                let mask = legal_mask(&game);
+                assert!(moves.iter().all(|next| mask[encode(&game, next)]));
+                game = *moves.choose(&mut rng).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn card_squares_tell_cards_apart() {
+        let space = ActionSpace::CardSquares;
+        for _ in 0..100 {
+            let game = random_game();
+            let mut positions: Vec<_> = game
+                .forward()
+                .map(|next| (next.my, next.other, next.cards, next.table))
+                .collect();
+            positions.sort_unstable();
+            positions.dedup();
+            let mut indices: Vec<_> = game.forward().map(|next| space.encode(&game, &next)).collect();
+            indices.sort_unstable();
+            indices.dedup();
+            // Every distinct move gets its own index.
+            assert_eq!(indices.len(), positions.len());
+        }
+    }
+
+    #[test]
+    fn notation() {
+        let space = ActionSpace::Squares;
+        assert_eq!(space.to_notation(0), "a1a1");
+        assert_eq!(space.to_notation(32), "b1c2");
+        assert_eq!(space.to_notation(624), "e5e5");
+        assert_eq!(space.from_notation("b1c2"), Some(32));
+        assert_eq!(space.from_notation("f1a1"), None);
+        assert_eq!(space.from_notation("a1a"), None);
+        assert_eq!(space.from_notation("b1c2/0"), None);
+        let space = ActionSpace::CardSquares;
+        assert_eq!(space.to_notation(625 + 32), "b1c2/1");
+        assert_eq!(space.from_notation("b1c2/0"), Some(32));
+        assert_eq!(space.from_notation("b1c2/2"), None);
+        assert_eq!(space.from_notation("b1c2"), None);
+    }
+}
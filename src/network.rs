@@ -1,92 +1,403 @@
-use std::fs;
-use std::sync::Arc;
+use std::{cell::RefCell, fs, ops::AddAssign, slice};
 
+use onitama_move_gen::gen::Game;
 use tensor::*;
 
-const LEARNING_RATE: f64 = 0.0001;
-const NETWORK_SIZE: usize = 3 * 3 * 8 * 64
-    + 5 * 5 * 64
-    + 3 * 3 * 64 * 64
-    + 5 * 5 * 64
-    + 3 * 3 * 64 * 64
-    + 5 * 5 * 64
-    + 3 * 3 * 64 * 64
-    + 5 * 5 * 64
-    + 1600 * 800
-    + 800
-    + 800 * 626
-    + 626;
+use crate::{
+    convert::{game_key, game_to_input, GameKey},
+    lru::LruCache,
+    model_file::{self, Architecture, LoadError, NamedTensor},
+    moves::{legal_mask, ActionSpace, POLICY_SIZE},
+    onnx,
+};
 
+/// Hidden units in the value head.
+const VALUE_HIDDEN: usize = 64;
+
+/// Positions whose evaluation is remembered by each network.
+const EVAL_CACHE_CAPACITY: usize = 4096;
+
+/// Loss of the network on training data.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Loss {
+    pub value: f64,
+    pub policy: f64,
+    /// L2 penalty on the weights.
+    pub regularization: f64,
+}
+
+impl AddAssign for Loss {
+    fn add_assign(&mut self, other: Loss) {
+        self.value += other.value;
+        self.policy += other.policy;
+        self.regularization += other.regularization;
+    }
+}
+
+/// FFT planner owned by a single network.
+/// Cloning creates a new planner, so that clones sent to other threads
+/// do not have to share one.
+struct Planner(RefCell<FftPlanner<f64>>);
+
+impl Planner {
+    fn new() -> Planner {
+        Planner(RefCell::new(FftPlanner::new()))
+    }
+}
+
+impl Clone for Planner {
+    fn clone(&self) -> Planner {
+        Planner::new()
+    }
+}
+
+/// Recently evaluated positions of a single network.
+/// Cloning creates an empty cache, the same as for `Planner`.
+struct EvalCache(RefCell<LruCache<GameKey, (Tensor1<f64, POLICY_SIZE>, f64)>>);
+
+impl EvalCache {
+    fn new(capacity: usize) -> EvalCache {
+        EvalCache(RefCell::new(LruCache::new(capacity)))
+    }
+}
+
+impl Clone for EvalCache {
+    fn clone(&self) -> EvalCache {
+        EvalCache::new(self.0.borrow().capacity())
+    }
+}
+
+/// How often `evaluate` could answer from the cache.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// Zero before any lookup.
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.
+        } else {
+            self.hits as f64 / lookups as f64
+        }
+    }
+}
+
+impl AddAssign for CacheStats {
+    fn add_assign(&mut self, other: CacheStats) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+    }
+}
+
+/// Two padded convolutions, each followed by batch normalization, with a skip
+/// connection around them.
+/// Also used for the gradients of a block.
+#[derive(Clone, Copy)]
+struct ResidualBlock<const C: usize>
+where
+    [(); 3 * 3 * C]: ,
+    [(); 5 * 5 * C]: ,
+{
+    kernels1: [Tensor3<f64, 3, 3, C>; C],
+    biases1: Tensor3<f64, 5, 5, C>,
+    norm1: BatchNorm<C>,
+    kernels2: [Tensor3<f64, 3, 3, C>; C],
+    biases2: Tensor3<f64, 5, 5, C>,
+    norm2: BatchNorm<C>,
+}
+
+/// Values from the feed-forward of a batch through a block which
+/// back-propagation needs.
+struct BlockTrace<const C: usize>
+where
+    [(); 5 * 5 * C]: ,
+{
+    inputs: Vec<Tensor3<f64, 5, 5, C>>,
+    /// Pre-activation of the first convolution, after normalization.
+    hidden: Vec<Tensor3<f64, 5, 5, C>>,
+    hidden_norm: BatchNormCache<5, 5, C>,
+    /// Pre-activation of the output, after adding the skip connection.
+    output: Vec<Tensor3<f64, 5, 5, C>>,
+    output_norm: BatchNormCache<5, 5, C>,
+}
+
+impl<const C: usize> ResidualBlock<C>
+where
+    [(); 3 * 3 * C]: ,
+    [(); 5 * 5 * C]: ,
+{
+    fn init() -> ResidualBlock<C> {
+        let distr = Init::HeNormal.distribution(3 * 3 * C, 3 * 3 * C);
+        ResidualBlock {
+            kernels1: [(); C].map(|()| Tensor3::rand(distr)),
+            biases1: Tensor3::default(),
+            norm1: BatchNorm::default(),
+            kernels2: [(); C].map(|()| Tensor3::rand(distr)),
+            biases2: Tensor3::default(),
+            norm2: BatchNorm::default(),
+        }
+    }
+
+    fn zero() -> ResidualBlock<C> {
+        ResidualBlock {
+            kernels1: [Tensor3::default(); C],
+            biases1: Tensor3::default(),
+            norm1: norm_gradients(Tensor1::default(), Tensor1::default()),
+            kernels2: [Tensor3::default(); C],
+            biases2: Tensor3::default(),
+            norm2: norm_gradients(Tensor1::default(), Tensor1::default()),
+        }
+    }
+
+    fn feed_forward_batch(
+        &self,
+        inputs: &[Tensor3<f64, 5, 5, C>],
+        mode: BatchNormMode,
+        fft_planner: &mut FftPlanner<f64>,
+    ) -> (Vec<Tensor3<f64, 5, 5, C>>, BlockTrace<C>) {
+        let x = Tensor3::convolution_pass_batch(inputs, &self.kernels1, &self.biases1, fft_planner);
+        let (hidden, hidden_norm) = self.norm1.forward_batch(&x, mode);
+        let a: Vec<_> = hidden.iter().map(|x| x.map(relu)).collect();
+        let x = Tensor3::convolution_pass_batch(&a, &self.kernels2, &self.biases2, fft_planner);
+        let (x, output_norm) = self.norm2.forward_batch(&x, mode);
+        let output: Vec<_> = x.into_iter().zip(inputs.iter()).map(|(x, input)| x + input).collect();
+        let trace = BlockTrace {
+            inputs: inputs.to_vec(),
+            hidden,
+            hidden_norm,
+            output,
+            output_norm,
+        };
+        (trace.output.iter().map(|x| x.map(relu)).collect(), trace)
+    }
+
+    /// Back-propagate a batch through the block.
+    /// Returns the gradients of the block and the derivatives for its inputs.
+    #[allow(non_snake_case)]
+    fn back_prop(
+        &self,
+        trace: &BlockTrace<C>,
+        output_derivatives: &[Tensor3<f64, 5, 5, C>],
+    ) -> (ResidualBlock<C>, Vec<Tensor3<f64, 5, 5, C>>) {
+        let dL_dx: Vec<_> = output_derivatives
+            .iter()
+            .zip(trace.output.iter())
+            .map(|(d, x)| *d * &x.map(d_relu))
+            .collect();
+        let (gamma2, beta2, dL_dx2) = self.norm2.backward(&trace.output_norm, &dL_dx);
+        let hidden_a: Vec<_> = trace.hidden.iter().map(|x| x.map(relu)).collect();
+        let (kernels2, biases2, dL_da) = bp_convolution_batch(&self.kernels2, &hidden_a, &dL_dx2);
+        let dL_dhidden: Vec<_> = dL_da
+            .iter()
+            .zip(trace.hidden.iter())
+            .map(|(d, x)| *d * &x.map(d_relu))
+            .collect();
+        let (gamma1, beta1, dL_dx1) = self.norm1.backward(&trace.hidden_norm, &dL_dhidden);
+        let (kernels1, biases1, dL_dinputs) = bp_convolution_batch(&self.kernels1, &trace.inputs, &dL_dx1);
+        let gradients = ResidualBlock {
+            kernels1,
+            biases1,
+            norm1: norm_gradients(gamma1, beta1),
+            kernels2,
+            biases2,
+            norm2: norm_gradients(gamma2, beta2),
+        };
+        // The skip connection passes the derivatives straight through.
+        let dL_dinputs = dL_dinputs
+            .into_iter()
+            .zip(dL_dx.iter())
+            .map(|(d, skip)| d + skip)
+            .collect();
+        (gradients, dL_dinputs)
+    }
+
+    fn params(&self) -> Vec<&[f64]> {
+        let mut params: Vec<&[f64]> = Vec::new();
+        params.extend(self.kernels1.iter().map(|t| &t.get_data_ref()[..]));
+        params.push(self.biases1.get_data_ref());
+        params.push(self.norm1.gamma.get_data_ref());
+        params.push(self.norm1.beta.get_data_ref());
+        params.extend(self.kernels2.iter().map(|t| &t.get_data_ref()[..]));
+        params.push(self.biases2.get_data_ref());
+        params.push(self.norm2.gamma.get_data_ref());
+        params.push(self.norm2.beta.get_data_ref());
+        params
+    }
+
+    fn params_mut(&mut self) -> Vec<&mut [f64]> {
+        let mut params: Vec<&mut [f64]> = Vec::new();
+        params.extend(self.kernels1.iter_mut().map(|t| &mut t.get_data_mut()[..]));
+        params.push(self.biases1.get_data_mut());
+        params.push(self.norm1.gamma.get_data_mut());
+        params.push(self.norm1.beta.get_data_mut());
+        params.extend(self.kernels2.iter_mut().map(|t| &mut t.get_data_mut()[..]));
+        params.push(self.biases2.get_data_mut());
+        params.push(self.norm2.gamma.get_data_mut());
+        params.push(self.norm2.beta.get_data_mut());
+        params
+    }
+}
+
+/// Batch normalization holding the derivatives for gamma and beta.
+/// Its running statistics are unused.
+fn norm_gradients<const C: usize>(gamma: Tensor1<f64, C>, beta: Tensor1<f64, C>) -> BatchNorm<C> {
+    BatchNorm {
+        gamma,
+        beta,
+        ..BatchNorm::default()
+    }
+}
+
+/// Values from the feed-forward of a batch through the input convolution and
+/// the tower which back-propagation needs.
+struct TowerTrace<const C: usize>
+where
+    [(); 5 * 5 * C]: ,
+{
+    /// Pre-activation of the input convolution, after normalization.
+    input_x: Vec<Tensor3<f64, 5, 5, C>>,
+    input_norm: BatchNormCache<5, 5, C>,
+    blocks: Vec<BlockTrace<C>>,
+}
+
+impl<const C: usize> TowerTrace<C>
+where
+    [(); 5 * 5 * C]: ,
+{
+    /// Statistics of every batch normalization, in the order of `norms`.
+    fn statistics(&self) -> Vec<BatchStatistics<C>> {
+        let mut statistics = vec![*self.input_norm.statistics()];
+        for block in self.blocks.iter() {
+            statistics.push(*block.hidden_norm.statistics());
+            statistics.push(*block.output_norm.statistics());
+        }
+        statistics
+    }
+}
+
+/// The policy and value heads on top of the tower.
+/// Also used for the gradients of the heads.
 #[derive(Clone)]
-pub struct Network {
-    fft_planner: Arc<FftPlanner<f64>>,
-    // Padded convolution layers.
-    l1_kernels: [Tensor3<f64, 3, 3, 8>; 64],
-    l1_biases: Tensor3<f64, 5, 5, 64>,
-    l2_kernels: [Tensor3<f64, 3, 3, 64>; 64],
-    l2_biases: Tensor3<f64, 5, 5, 64>,
-    l3_kernels: [Tensor3<f64, 3, 3, 64>; 64],
-    l3_biases: Tensor3<f64, 5, 5, 64>,
-    l4_kernels: [Tensor3<f64, 3, 3, 64>; 64],
-    l4_biases: Tensor3<f64, 5, 5, 64>,
-    // Fully connected layers.
-    l5_weights: [Tensor1<f64, 1600>; 800],
-    l5_biases: Tensor1<f64, 800>,
-    l6_weights: [Tensor1<f64, 800>; 626],
-    l6_biases: Tensor1<f64, 626>,
-    /* 626 outputs
-     * 625 for each from-to move combination
-     * 1 output for board evaluation */
-}
-
-impl Network {
-    pub fn init() -> Network {
-        let distr = rand_distr::Standard;
-        Network {
-            fft_planner: Arc::new(FftPlanner::new()),
-            // Padded convolution layers.
-            l1_kernels: [(); 64].map(|()| Tensor3::rand(distr)),
-            l1_biases: Tensor3::rand(distr),
-            l2_kernels: [(); 64].map(|()| Tensor3::rand(distr)),
-            l2_biases: Tensor3::rand(distr),
-            l3_kernels: [(); 64].map(|()| Tensor3::rand(distr)),
-            l3_biases: Tensor3::rand(distr),
-            l4_kernels: [(); 64].map(|()| Tensor3::rand(distr)),
-            l4_biases: Tensor3::rand(distr),
-            // Fully connected layers.
-            l5_weights: [(); 800].map(|()| Tensor1::rand(distr)),
-            l5_biases: Tensor1::rand(distr),
-            l6_weights: [(); 626].map(|()| Tensor1::rand(distr)),
-            l6_biases: Tensor1::rand(distr),
-        }
-    }
-
-    pub fn feed_forward(&self, input: Tensor3<f64, 5, 5, 8>) -> (Tensor1<f64, 625>, f64) {
-        let (vec, board_eval) = input
-            .convolution_pass(&self.l1_kernels, &self.l1_biases, &mut self.fft_planner)
-            .map(relu)
-            .convolution_pass(&self.l2_kernels, &self.l2_biases, &mut self.fft_planner)
-            .map(relu)
-            .convolution_pass(&self.l3_kernels, &self.l3_biases, &mut self.fft_planner)
-            .map(relu)
-            .convolution_pass(&self.l4_kernels, &self.l4_biases, &mut self.fft_planner)
-            .map(relu)
-            .reshape::<Tensor1<_, 1600>>()
-            .fully_connected_pass(&self.l5_weights, &self.l5_biases)
-            .map(relu)
-            .fully_connected_pass(&self.l6_weights, &self.l6_biases)
-            .split_last();
-        (vec.softmax(), 2. * sig(board_eval) - 1.)
+struct Heads<const C: usize>
+where
+    [(); 5 * 5 * C]: ,
+    [(); 1 * 1 * C]: ,
+{
+    // Policy head.
+    policy_kernels: [Tensor3<f64, 1, 1, C>; 2],
+    policy_kernel_biases: Tensor3<f64, 5, 5, 2>,
+    policy_weights: [Tensor1<f64, 50>; POLICY_SIZE],
+    policy_biases: Tensor1<f64, POLICY_SIZE>,
+    // Value head.
+    value_kernels: [Tensor3<f64, 1, 1, C>; 1],
+    value_kernel_biases: Tensor3<f64, 5, 5, 1>,
+    value_hidden_weights: [Tensor1<f64, 25>; VALUE_HIDDEN],
+    value_hidden_biases: Tensor1<f64, VALUE_HIDDEN>,
+    value_weights: [Tensor1<f64, VALUE_HIDDEN>; 1],
+    value_biases: Tensor1<f64, 1>,
+}
+
+impl<const C: usize> Heads<C>
+where
+    [(); 5 * 5 * C]: ,
+    [(); 1 * 1 * C]: ,
+{
+    /// He initialization before relu, Xavier before the softmax and tanh.
+    fn init() -> Heads<C> {
+        let policy_kernels = Init::HeNormal.distribution(C, 2);
+        let policy_weights = Init::XavierNormal.distribution(50, POLICY_SIZE);
+        let value_kernels = Init::HeNormal.distribution(C, 1);
+        let value_hidden_weights = Init::HeNormal.distribution(25, VALUE_HIDDEN);
+        let value_weights = Init::XavierNormal.distribution(VALUE_HIDDEN, 1);
+        Heads {
+            // Policy head.
+            policy_kernels: [(); 2].map(|()| Tensor3::rand(policy_kernels)),
+            policy_kernel_biases: Tensor3::default(),
+            policy_weights: [(); POLICY_SIZE].map(|()| Tensor1::rand(policy_weights)),
+            policy_biases: Tensor1::default(),
+            // Value head.
+            value_kernels: [(); 1].map(|()| Tensor3::rand(value_kernels)),
+            value_kernel_biases: Tensor3::default(),
+            value_hidden_weights: [(); VALUE_HIDDEN].map(|()| Tensor1::rand(value_hidden_weights)),
+            value_hidden_biases: Tensor1::default(),
+            value_weights: [(); 1].map(|()| Tensor1::rand(value_weights)),
+            value_biases: Tensor1::default(),
+        }
     }
 
-    #[allow(non_snake_case, clippy::many_single_char_names)]
-    pub fn back_prop(&mut self, input: Tensor3<f64, 5, 5, 8>, pi: Tensor1<f64, 625>, z: f64) -> f64 {
-        // Some resources:
-        // https://youtu.be/Ilg3gGewQ5U
-        // http://neuralnetworksanddeeplearning.com/chap2.html
-        // https://eli.thegreenplace.net/2016/the-softmax-function-and-its-derivative/
-        // https://medium.com/@pavisj/convolutions-and-backpropagations-46026a8f5d2c
+    fn zero() -> Heads<C> {
+        Heads {
+            // Policy head.
+            policy_kernels: [Tensor3::default(); 2],
+            policy_kernel_biases: Tensor3::default(),
+            policy_weights: [Tensor1::default(); POLICY_SIZE],
+            policy_biases: Tensor1::default(),
+            // Value head.
+            value_kernels: [Tensor3::default(); 1],
+            value_kernel_biases: Tensor3::default(),
+            value_hidden_weights: [Tensor1::default(); VALUE_HIDDEN],
+            value_hidden_biases: Tensor1::default(),
+            value_weights: [Tensor1::default(); 1],
+            value_biases: Tensor1::default(),
+        }
+    }
+
+    fn feed_forward_batch(
+        &self,
+        x: &[Tensor3<f64, 5, 5, C>],
+        masks: &[[bool; POLICY_SIZE]],
+        fft_planner: &mut FftPlanner<f64>,
+    ) -> Vec<(Tensor1<f64, POLICY_SIZE>, f64)> {
+        // Policy head.
+        let policy = Tensor3::convolution_pass_batch(
+            x,
+            &self.policy_kernels,
+            &self.policy_kernel_biases,
+            fft_planner,
+        );
+        let policy: Vec<_> = policy
+            .into_iter()
+            .map(|x| x.map(relu).reshape::<Tensor1<_, 50>>())
+            .collect();
+        let policy = Tensor1::fully_connected_pass_batch(&policy, &self.policy_weights, &self.policy_biases);
+        // Value head.
+        let value =
+            Tensor3::convolution_pass_batch(x, &self.value_kernels, &self.value_kernel_biases, fft_planner);
+        let value: Vec<_> = value
+            .into_iter()
+            .map(|x| x.map(relu).reshape::<Tensor1<_, 25>>())
+            .collect();
+        let value = Tensor1::fully_connected_pass_batch(
+            &value,
+            &self.value_hidden_weights,
+            &self.value_hidden_biases,
+        );
+        let value: Vec<_> = value.into_iter().map(|x| x.map(relu)).collect();
+        let value = Tensor1::fully_connected_pass_batch(&value, &self.value_weights, &self.value_biases);
+        policy
+            .into_iter()
+            .zip(masks.iter())
+            .zip(value.into_iter())
+            .map(|((policy, mask), value)| (policy.masked_softmax(mask), value.nth(0).tanh()))
+            .collect()
+    }
 
+    /// Calculate the gradients of the heads and the loss for a single example,
+    /// given the output of the tower `tower_a`.
+    /// Returns the gradients, the loss and the derivatives for `tower_a`.
+    #[allow(non_snake_case, clippy::many_single_char_names)]
+    fn gradients(
+        &self,
+        tower_a: Tensor3<f64, 5, 5, C>,
+        mask: &[bool; POLICY_SIZE],
+        mut pi: Tensor1<f64, POLICY_SIZE>,
+        z: f64,
+        fft_planner: &mut FftPlanner<f64>,
+    ) -> (Heads<C>, Loss, Tensor3<f64, 5, 5, C>) {
         // We want to minimize the cost L.
         // L = (z - v)^2 - pi . log(p)
         // where
@@ -98,70 +409,531 @@ impl Network {
         // Feed-forward while keeping track of intermediate values.
         // x is pre-activation.
         // a is activation.
-        let l1_x = input.convolution_pass(&self.l1_kernels, &self.l1_biases, &mut self.fft_planner);
-        let l1_a = l1_x.map(relu);
-        let l2_x = l1_a.convolution_pass(&self.l2_kernels, &self.l2_biases, &mut self.fft_planner);
-        let l2_a = l2_x.map(relu);
-        let l3_x = l2_a.convolution_pass(&self.l3_kernels, &self.l3_biases, &mut self.fft_planner);
-        let l3_a = l3_x.map(relu);
-        let l4_x = l3_a.convolution_pass(&self.l4_kernels, &self.l4_biases, &mut self.fft_planner);
-        let l4_a = l4_x.map(relu).reshape::<Tensor1<_, 1600>>();
-        let l5_x = l4_a.fully_connected_pass(&self.l5_weights, &self.l5_biases);
-        let l5_a = l5_x.map(relu);
-        let l6_x = l5_a.fully_connected_pass(&self.l6_weights, &self.l6_biases);
-        let (o, b) = l6_x.split_last();
-        let p = o.softmax();
+        // Policy head.
+        let policy_x =
+            tower_a.convolution_pass(&self.policy_kernels, &self.policy_kernel_biases, fft_planner);
+        let policy_a = policy_x.map(relu).reshape::<Tensor1<_, 50>>();
+        let o = policy_a.fully_connected_pass(&self.policy_weights, &self.policy_biases);
+        let p = o.masked_softmax(mask);
+        // Value head.
+        let value_x = tower_a.convolution_pass(&self.value_kernels, &self.value_kernel_biases, fft_planner);
+        let value_a = value_x.map(relu).reshape::<Tensor1<_, 25>>();
+        let hidden_x = value_a.fully_connected_pass(&self.value_hidden_weights, &self.value_hidden_biases);
+        let hidden_a = hidden_x.map(relu);
+        let b = hidden_a.fully_connected_pass(&self.value_weights, &self.value_biases).nth(0);
         let v = b.tanh();
+        // Illegal moves are not part of the softmax, so they are not targets.
+        for (target, &legal) in pi.get_data_mut().iter_mut().zip(mask.iter()) {
+            if !legal {
+                *target = 0.;
+            }
+        }
         // The cost function.
-        let L = (z - v).powi(2) - (pi * &p.map(f64::ln)).sum();
+        let L = Loss {
+            value: (z - v).powi(2),
+            // p is zero for illegal moves, which are left out.
+            policy: -(pi * &p.map(|p| p.max(f64::MIN_POSITIVE).ln())).sum(),
+            regularization: 0.,
+        };
 
         // Begin calculating partial derivatives.
         let dL_dv = 2. * (v - z);
         let dL_db = dL_dv * (1. / b.cosh().powi(2)); // tanh'(x) = sech^2(x)
-        let dL_do = p - &pi; // Use log trick with derivative of softmax.
+        // Use log trick with derivative of softmax. Illegal moves get no gradient
+        // because both p and pi are zero there.
+        let dL_do = p.scale(pi.sum()) - &pi;
+
+        // Policy head.
+        let (policy_weights, policy_biases, dL_da) =
+            bp_fully_connected(&self.policy_weights, policy_a, dL_do);
+        let dL_dx = dL_da.reshape::<Tensor3<_, 5, 5, 2>>() * &policy_x.map(d_relu);
+        let (policy_kernels, policy_kernel_biases, policy_dL_da) =
+            bp_convolution(&self.policy_kernels, tower_a, dL_dx);
+        // Value head.
+        let (value_weights, value_biases, dL_da) =
+            bp_fully_connected(&self.value_weights, hidden_a, Tensor1::new([dL_db]));
+        let (value_hidden_weights, value_hidden_biases, dL_da) =
+            bp_fully_connected(&self.value_hidden_weights, value_a, dL_da * &hidden_x.map(d_relu));
+        let dL_dx = dL_da.reshape::<Tensor3<_, 5, 5, 1>>() * &value_x.map(d_relu);
+        let (value_kernels, value_kernel_biases, value_dL_da) =
+            bp_convolution(&self.value_kernels, tower_a, dL_dx);
+
+        let gradients = Heads {
+            policy_kernels,
+            policy_kernel_biases,
+            policy_weights,
+            policy_biases,
+            value_kernels,
+            value_kernel_biases,
+            value_hidden_weights,
+            value_hidden_biases,
+            value_weights,
+            value_biases,
+        };
+        // Both heads read the output of the tower.
+        (gradients, L, policy_dL_da + &value_dL_da)
+    }
 
-        // Combine dl_do and dl_db.
-        let dL_dx = {
-            let mut data = [0.; 626];
-            for (elem, val) in data.iter_mut().zip(dL_do.into_iter()) {
-                *elem = val;
+    fn params(&self) -> Vec<&[f64]> {
+        let mut params: Vec<&[f64]> = Vec::new();
+        params.extend(self.policy_kernels.iter().map(|t| &t.get_data_ref()[..]));
+        params.push(self.policy_kernel_biases.get_data_ref());
+        params.extend(self.policy_weights.iter().map(|t| &t.get_data_ref()[..]));
+        params.push(self.policy_biases.get_data_ref());
+        params.extend(self.value_kernels.iter().map(|t| &t.get_data_ref()[..]));
+        params.push(self.value_kernel_biases.get_data_ref());
+        params.extend(self.value_hidden_weights.iter().map(|t| &t.get_data_ref()[..]));
+        params.push(self.value_hidden_biases.get_data_ref());
+        params.extend(self.value_weights.iter().map(|t| &t.get_data_ref()[..]));
+        params.push(self.value_biases.get_data_ref());
+        params
+    }
+
+    fn params_mut(&mut self) -> Vec<&mut [f64]> {
+        let mut params: Vec<&mut [f64]> = Vec::new();
+        params.extend(self.policy_kernels.iter_mut().map(|t| &mut t.get_data_mut()[..]));
+        params.push(self.policy_kernel_biases.get_data_mut());
+        params.extend(self.policy_weights.iter_mut().map(|t| &mut t.get_data_mut()[..]));
+        params.push(self.policy_biases.get_data_mut());
+        params.extend(self.value_kernels.iter_mut().map(|t| &mut t.get_data_mut()[..]));
+        params.push(self.value_kernel_biases.get_data_mut());
+        params.extend(self.value_hidden_weights.iter_mut().map(|t| &mut t.get_data_mut()[..]));
+        params.push(self.value_hidden_biases.get_data_mut());
+        params.extend(self.value_weights.iter_mut().map(|t| &mut t.get_data_mut()[..]));
+        params.push(self.value_biases.get_data_mut());
+        params
+    }
+}
+
+impl<const C: usize> AddAssign<&Heads<C>> for Heads<C>
+where
+    [(); 5 * 5 * C]: ,
+    [(); 1 * 1 * C]: ,
+{
+    fn add_assign(&mut self, other: &Heads<C>) {
+        for (grads, other) in self.params_mut().into_iter().zip(other.params().into_iter()) {
+            for (grad, val) in grads.iter_mut().zip(other.iter()) {
+                *grad += val;
             }
-            data[625] = dL_db;
-            Tensor1::new(data)
+        }
+    }
+}
+
+/// Residual network with a policy and a value head.
+///
+/// The input goes through a padded convolution to `C` channels and then a
+/// tower of residual blocks. Every convolution up to here is followed by batch
+/// normalization. The policy head is a 1x1 convolution to 2 channels followed
+/// by a fully connected layer to the moves. The value head is a 1x1
+/// convolution to a single channel followed by a hidden fully connected layer
+/// and the output.
+#[derive(Clone)]
+pub struct Network<const C: usize>
+where
+    [(); 3 * 3 * C]: ,
+    [(); 5 * 5 * C]: ,
+    [(); 1 * 1 * C]: ,
+{
+    fft_planner: Planner,
+    cache: EvalCache,
+    // Input convolution.
+    input_kernels: [Tensor3<f64, 3, 3, 8>; C],
+    input_biases: Tensor3<f64, 5, 5, C>,
+    input_norm: BatchNorm<C>,
+    // Residual tower.
+    tower: Vec<ResidualBlock<C>>,
+    // Policy and value heads.
+    heads: Heads<C>,
+}
+
+impl<const C: usize> Network<C>
+where
+    [(); 3 * 3 * C]: ,
+    [(); 5 * 5 * C]: ,
+    [(); 1 * 1 * C]: ,
+{
+    /// Randomly initialized network with `blocks` residual blocks.
+    /// The weights are scaled to the size of their layer and the biases are
+    /// zero.
+    pub fn init(blocks: usize) -> Network<C> {
+        let distr = Init::HeNormal.distribution(3 * 3 * 8, 3 * 3 * C);
+        Network {
+            fft_planner: Planner::new(),
+            cache: EvalCache::new(EVAL_CACHE_CAPACITY),
+            // Input convolution.
+            input_kernels: [(); C].map(|()| Tensor3::rand(distr)),
+            input_biases: Tensor3::default(),
+            input_norm: BatchNorm::default(),
+            // Residual tower.
+            tower: (0..blocks).map(|_| ResidualBlock::init()).collect(),
+            // Policy and value heads.
+            heads: Heads::init(),
+        }
+    }
+
+    /// Number of residual blocks in the tower.
+    pub fn blocks(&self) -> usize {
+        self.tower.len()
+    }
+
+    /// Every batch normalization, in the same order as the statistics in
+    /// `Gradients`.
+    fn norms(&self) -> Vec<&BatchNorm<C>> {
+        let mut norms = vec![&self.input_norm];
+        for block in self.tower.iter() {
+            norms.push(&block.norm1);
+            norms.push(&block.norm2);
+        }
+        norms
+    }
+
+    /// Mutable version of `norms`.
+    fn norms_mut(&mut self) -> Vec<&mut BatchNorm<C>> {
+        let mut norms = vec![&mut self.input_norm];
+        for block in self.tower.iter_mut() {
+            norms.push(&mut block.norm1);
+            norms.push(&mut block.norm2);
+        }
+        norms
+    }
+
+    /// Get the policy and eval for an input. The policy is a softmax over the
+    /// moves set in `mask` and zero for the rest.
+    pub fn feed_forward(
+        &self,
+        input: Tensor3<f64, 5, 5, 8>,
+        mask: &[bool; POLICY_SIZE],
+    ) -> (Tensor1<f64, POLICY_SIZE>, f64) {
+        self.feed_forward_batch(&[input], slice::from_ref(mask)).pop().unwrap()
+    }
+
+    /// Get the policy and eval of a position, using the cache when possible.
+    pub fn evaluate(&self, game: &Game) -> (Tensor1<f64, POLICY_SIZE>, f64) {
+        let key = game_key(game);
+        if let Some(&eval) = self.cache.0.borrow_mut().get(&key) {
+            return eval;
+        }
+        let eval = self.feed_forward(game_to_input(game), &legal_mask(game));
+        self.cache.0.borrow_mut().insert(key, eval);
+        eval
+    }
+
+    /// Like `evaluate`, but positions which are not cached are fed forward
+    /// together in one batch.
+    pub fn evaluate_batch(&self, games: &[Game]) -> Vec<(Tensor1<f64, POLICY_SIZE>, f64)> {
+        let mut cache = self.cache.0.borrow_mut();
+        let cached: Vec<_> = games.iter().map(|game| cache.get(&game_key(game)).copied()).collect();
+        let uncached: Vec<_> = games
+            .iter()
+            .zip(cached.iter())
+            .filter(|(_, cached)| cached.is_none())
+            .map(|(game, _)| game)
+            .collect();
+        let inputs: Vec<_> = uncached.iter().map(|game| game_to_input(game)).collect();
+        let masks: Vec<_> = uncached.iter().map(|game| legal_mask(game)).collect();
+        let mut evals = self.feed_forward_batch(&inputs, &masks).into_iter();
+        games
+            .iter()
+            .zip(cached.into_iter())
+            .map(|(game, cached)| {
+                cached.unwrap_or_else(|| {
+                    let eval = evals.next().unwrap();
+                    cache.insert(game_key(game), eval);
+                    eval
+                })
+            })
+            .collect()
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        let cache = self.cache.0.borrow();
+        CacheStats {
+            hits: cache.hits(),
+            misses: cache.misses(),
+        }
+    }
+
+    /// Feed-forward a whole batch of inputs at once.
+    /// Much cheaper per input than calling `feed_forward` for each of them.
+    /// Batch normalization uses the running statistics, so every input gets
+    /// the same result as on its own.
+    pub fn feed_forward_batch(
+        &self,
+        inputs: &[Tensor3<f64, 5, 5, 8>],
+        masks: &[[bool; POLICY_SIZE]],
+    ) -> Vec<(Tensor1<f64, POLICY_SIZE>, f64)> {
+        assert_eq!(inputs.len(), masks.len());
+        let fft_planner = &mut *self.fft_planner.0.borrow_mut();
+        let (x, _) = self.feed_forward_tower(inputs, BatchNormMode::Inference, fft_planner);
+        self.heads.feed_forward_batch(&x, masks, fft_planner)
+    }
+
+    /// Feed-forward a batch through the input convolution and the tower.
+    fn feed_forward_tower(
+        &self,
+        inputs: &[Tensor3<f64, 5, 5, 8>],
+        mode: BatchNormMode,
+        fft_planner: &mut FftPlanner<f64>,
+    ) -> (Vec<Tensor3<f64, 5, 5, C>>, TowerTrace<C>) {
+        let x = Tensor3::convolution_pass_batch(inputs, &self.input_kernels, &self.input_biases, fft_planner);
+        let (input_x, input_norm) = self.input_norm.forward_batch(&x, mode);
+        let mut a: Vec<_> = input_x.iter().map(|x| x.map(relu)).collect();
+        let mut blocks = Vec::with_capacity(self.tower.len());
+        for block in self.tower.iter() {
+            let (block_a, trace) = block.feed_forward_batch(&a, mode, fft_planner);
+            blocks.push(trace);
+            a = block_a;
+        }
+        let trace = TowerTrace {
+            input_x,
+            input_norm,
+            blocks,
         };
+        (a, trace)
+    }
 
-        let dL_da = bp_fully_connected(&mut self.l6_weights, &mut self.l6_biases, l5_a, dL_dx);
-        let dL_da = bp_fully_connected(&mut self.l5_weights, &mut self.l5_biases, l4_a, dL_da.map(d_relu));
-        let dL_da = bp_convolution(
-            &mut self.l4_kernels,
-            &mut self.l4_biases,
-            l3_a,
-            dL_da.map(d_relu).reshape(),
-        );
-        let dL_da = bp_convolution(&mut self.l3_kernels, &mut self.l3_biases, l2_a, dL_da.map(d_relu));
-        let dL_da = bp_convolution(&mut self.l2_kernels, &mut self.l2_biases, l1_a, dL_da.map(d_relu));
-        let _ = bp_convolution(
-            &mut self.l1_kernels,
-            &mut self.l1_biases,
-            input,
-            dL_da.map(d_relu),
-        );
+    /// Calculate the gradients of the loss averaged over a batch, and the
+    /// summed loss. Only the moves set in the mask of an example take part in
+    /// its policy. Batch normalization uses the statistics of the batch, which
+    /// are kept in the gradients for `apply`.
+    /// The network itself is left unchanged.
+    #[allow(non_snake_case)]
+    pub fn gradients(
+        &self,
+        batch: &[(Tensor3<f64, 5, 5, 8>, [bool; POLICY_SIZE], Tensor1<f64, POLICY_SIZE>, f64)],
+    ) -> (Gradients<C>, Loss) {
+        // Some resources:
+        // https://youtu.be/Ilg3gGewQ5U
+        // http://neuralnetworksanddeeplearning.com/chap2.html
+        // https://eli.thegreenplace.net/2016/the-softmax-function-and-its-derivative/
+        // https://medium.com/@pavisj/convolutions-and-backpropagations-46026a8f5d2c
+        // https://arxiv.org/abs/1502.03167
+        let fft_planner = &mut *self.fft_planner.0.borrow_mut();
+        let inputs: Vec<_> = batch.iter().map(|(input, ..)| *input).collect();
+        let (tower_a, trace) = self.feed_forward_tower(&inputs, BatchNormMode::Train, fft_planner);
+        // The heads have no batch normalization, so every example goes through
+        // them on its own.
+        let mut heads = Heads::zero();
+        let mut loss = Loss::default();
+        let mut dL_da = Vec::with_capacity(batch.len());
+        for (&a, (_, mask, pi, z)) in tower_a.iter().zip(batch.iter()) {
+            let (example_heads, example_loss, example_dL_da) =
+                self.heads.gradients(a, mask, *pi, *z, fft_planner);
+            heads += &example_heads;
+            loss += example_loss;
+            dL_da.push(example_dL_da);
+        }
+        // Residual tower, from the last block back to the first.
+        let mut tower = Vec::with_capacity(self.tower.len());
+        for (block, block_trace) in self.tower.iter().zip(trace.blocks.iter()).rev() {
+            let (gradients, dL_dinputs) = block.back_prop(block_trace, &dL_da);
+            tower.push(gradients);
+            dL_da = dL_dinputs;
+        }
+        tower.reverse();
+        // Input convolution.
+        let dL_dx: Vec<_> = dL_da
+            .iter()
+            .zip(trace.input_x.iter())
+            .map(|(d, x)| *d * &x.map(d_relu))
+            .collect();
+        let (gamma, beta, dL_dx) = self.input_norm.backward(&trace.input_norm, &dL_dx);
+        let (input_kernels, input_biases, _) = bp_convolution_batch(&self.input_kernels, &inputs, &dL_dx);
 
+        let mut gradients = Gradients {
+            input_kernels,
+            input_biases,
+            input_norm: norm_gradients(gamma, beta),
+            tower,
+            heads,
+            statistics: trace.statistics(),
+        };
+        // So far these are the derivatives of the summed loss.
+        gradients.scale(1. / batch.len() as f64);
+        (gradients, loss)
+    }
+
+    /// Update the weights using the optimizer, and the running statistics of
+    /// batch normalization using the statistics in the gradients.
+    /// Cached evaluations are dropped since they belong to the old weights.
+    pub fn apply<O: Optimizer>(&mut self, gradients: &Gradients<C>, optimizer: &mut O) {
+        self.cache.0.get_mut().clear();
+        optimizer.next_step();
+        for (slot, (params, grads)) in self
+            .params_mut()
+            .into_iter()
+            .zip(gradients.params().into_iter())
+            .enumerate()
+        {
+            optimizer.update(slot, params, grads);
+        }
+        for (norm, statistics) in self.norms_mut().into_iter().zip(gradients.statistics.iter()) {
+            norm.update_running(statistics);
+        }
+    }
+
+    /// Add the L2 penalty `c * |w|^2` over all parameters to the gradients.
+    /// Returns the penalty.
+    pub fn weight_decay(&self, gradients: &mut Gradients<C>, c: f64) -> f64 {
+        let mut penalty = 0.;
+        for (params, grads) in self.params().into_iter().zip(gradients.params_mut().into_iter()) {
+            for (param, grad) in params.iter().zip(grads.iter_mut()) {
+                penalty += c * param * param;
+                *grad += 2. * c * param;
+            }
+        }
+        penalty
+    }
+
+    pub fn back_prop<O: Optimizer>(
+        &mut self,
+        input: Tensor3<f64, 5, 5, 8>,
+        mask: &[bool; POLICY_SIZE],
+        pi: Tensor1<f64, POLICY_SIZE>,
+        z: f64,
+        optimizer: &mut O,
+        weight_decay: f64,
+    ) -> Loss {
         // Return loss just to track if it is going down.
-        L
+        self.back_prop_batch(&[(input, *mask, pi, z)], optimizer, weight_decay)
+    }
+
+    /// Back-propagate a whole mini-batch and apply the averaged gradients once.
+    /// Returns the summed loss of the batch.
+    pub fn back_prop_batch<O: Optimizer>(
+        &mut self,
+        batch: &[(Tensor3<f64, 5, 5, 8>, [bool; POLICY_SIZE], Tensor1<f64, POLICY_SIZE>, f64)],
+        optimizer: &mut O,
+        weight_decay: f64,
+    ) -> Loss {
+        let (mut gradients, mut loss) = self.gradients(batch);
+        // The penalty is the same for every example in the batch.
+        loss.regularization = batch.len() as f64 * self.weight_decay(&mut gradients, weight_decay);
+        self.apply(&gradients, optimizer);
+        loss
     }
 }
 
+/// Partial derivatives of the loss with respect to every parameter.
+/// Mirrors the layers of `Network`.
+#[derive(Clone)]
+pub struct Gradients<const C: usize>
+where
+    [(); 3 * 3 * C]: ,
+    [(); 5 * 5 * C]: ,
+    [(); 1 * 1 * C]: ,
+{
+    // Input convolution.
+    input_kernels: [Tensor3<f64, 3, 3, 8>; C],
+    input_biases: Tensor3<f64, 5, 5, C>,
+    input_norm: BatchNorm<C>,
+    // Residual tower.
+    tower: Vec<ResidualBlock<C>>,
+    // Policy and value heads.
+    heads: Heads<C>,
+    /// Statistics of the batch at every batch normalization, which `apply`
+    /// moves the running statistics towards. Empty for zero gradients.
+    statistics: Vec<BatchStatistics<C>>,
+}
+
+impl<const C: usize> Gradients<C>
+where
+    [(); 3 * 3 * C]: ,
+    [(); 5 * 5 * C]: ,
+    [(); 1 * 1 * C]: ,
+{
+    /// Zero gradients for a network with `blocks` residual blocks.
+    pub fn zero(blocks: usize) -> Gradients<C> {
+        Gradients {
+            // Input convolution.
+            input_kernels: [Tensor3::default(); C],
+            input_biases: Tensor3::default(),
+            input_norm: norm_gradients(Tensor1::default(), Tensor1::default()),
+            // Residual tower.
+            tower: vec![ResidualBlock::zero(); blocks],
+            // Policy and value heads.
+            heads: Heads::zero(),
+            statistics: Vec::new(),
+        }
+    }
+
+    /// Multiply all gradients by a scalar.
+    pub fn scale(&mut self, scalar: f64) {
+        for grads in self.params_mut() {
+            grads.iter_mut().for_each(|grad| *grad *= scalar);
+        }
+    }
+}
+
+/// Only the gradients are added, the batch statistics are left as they are.
+impl<const C: usize> AddAssign<&Gradients<C>> for Gradients<C>
+where
+    [(); 3 * 3 * C]: ,
+    [(); 5 * 5 * C]: ,
+    [(); 1 * 1 * C]: ,
+{
+    fn add_assign(&mut self, other: &Gradients<C>) {
+        for (grads, other) in self.params_mut().into_iter().zip(other.params().into_iter()) {
+            for (grad, val) in grads.iter_mut().zip(other.iter()) {
+                *grad += val;
+            }
+        }
+    }
+}
+
+// Network and Gradients share the names of their parameter tensors.
+macro_rules! impl_params {
+    ($type:ident) => {
+        impl<const C: usize> $type<C>
+        where
+            [(); 3 * 3 * C]: ,
+            [(); 5 * 5 * C]: ,
+            [(); 1 * 1 * C]: ,
+        {
+            /// All parameter tensors as flat slices, always in the same order.
+            fn params(&self) -> Vec<&[f64]> {
+                let mut params: Vec<&[f64]> = Vec::new();
+                params.extend(self.input_kernels.iter().map(|t| &t.get_data_ref()[..]));
+                params.push(self.input_biases.get_data_ref());
+                params.push(self.input_norm.gamma.get_data_ref());
+                params.push(self.input_norm.beta.get_data_ref());
+                for block in self.tower.iter() {
+                    params.extend(block.params());
+                }
+                params.extend(self.heads.params());
+                params
+            }
+
+            /// Mutable version of `params`.
+            fn params_mut(&mut self) -> Vec<&mut [f64]> {
+                let mut params: Vec<&mut [f64]> = Vec::new();
+                params.extend(self.input_kernels.iter_mut().map(|t| &mut t.get_data_mut()[..]));
+                params.push(self.input_biases.get_data_mut());
+                params.push(self.input_norm.gamma.get_data_mut());
+                params.push(self.input_norm.beta.get_data_mut());
+                for block in self.tower.iter_mut() {
+                    params.extend(block.params_mut());
+                }
+                params.extend(self.heads.params_mut());
+                params
+            }
+        }
+    };
+}
+
+impl_params!(Network);
+impl_params!(Gradients);
+
 /// Do back-propagation for a fully connected layer.
+/// Returns the derivatives for the weights, biases and previous layer.
 fn bp_fully_connected<const A: usize, const B: usize>(
-    weights: &mut [Tensor1<f64, A>; B],
-    biases: &mut Tensor1<f64, B>,
+    weights: &[Tensor1<f64, A>; B],
     prev_activations: Tensor1<f64, A>,
     next_layer_derivatives: Tensor1<f64, B>,
-) -> Tensor1<f64, A> {
-    let change_in_weights = {
+) -> ([Tensor1<f64, A>; B], Tensor1<f64, B>, Tensor1<f64, A>) {
+    let weight_derivatives = {
         let mut iter = next_layer_derivatives.iter();
-        [(); B].map(|()| prev_activations.scale(iter.next().unwrap() * LEARNING_RATE))
+        [(); B].map(|()| prev_activations.scale(*iter.next().unwrap()))
     };
     let prev_layer_derivatives = {
         let mut data = [0.; A];
@@ -173,34 +945,26 @@ fn bp_fully_connected<const A: usize, const B: usize>(
         Tensor1::new(data)
     };
 
-    weights
-        .iter_mut()
-        .zip(change_in_weights.iter())
-        .for_each(|(weights, adjustment)| *weights -= adjustment);
-    *biases -= &next_layer_derivatives.scale(LEARNING_RATE);
-
-    prev_layer_derivatives
+    (weight_derivatives, next_layer_derivatives, prev_layer_derivatives)
 }
 
-/// Do back-propagation for a convolution layer.
-fn bp_convolution<const A: usize, const B: usize>(
-    kernels: &mut [Tensor3<f64, 3, 3, A>; B],
-    biases: &mut Tensor3<f64, 5, 5, B>,
+/// Do back-propagation for a padded convolution layer with `K` by `K` kernels.
+/// Returns the derivatives for the kernels, biases and previous layer.
+fn bp_convolution<const K: usize, const A: usize, const B: usize>(
+    kernels: &[Tensor3<f64, K, K, A>; B],
     prev_activations: Tensor3<f64, 5, 5, A>,
     next_layer_derivatives: Tensor3<f64, 5, 5, B>,
-) -> Tensor3<f64, 5, 5, A>
+) -> ([Tensor3<f64, K, K, A>; B], Tensor3<f64, 5, 5, B>, Tensor3<f64, 5, 5, A>)
 where
-    [(); 3 * 3 * A]: ,
+    [(); K * K * A]: ,
     [(); 5 * 5 * A]: ,
     [(); 5 * 5 * B]: ,
 {
-    let change_in_kernels = {
-        let mut iter = 0..64;
-        [(); 64].map(|()| {
+    let kernel_derivatives = {
+        let mut iter = 0..B;
+        [(); B].map(|()| {
             let i = iter.next().unwrap();
-            prev_activations
-                .convolve_with_pad_to(next_layer_derivatives.slice::<5, 5, 1>(0, 0, i))
-                .scale(LEARNING_RATE)
+            prev_activations.convolve_with_pad_to(next_layer_derivatives.slice::<5, 5, 1>(0, 0, i))
         })
     };
     let prev_layer_derivatives = {
@@ -213,107 +977,657 @@ where
         }
         res
     };
-    kernels
-        .iter_mut()
-        .zip(change_in_kernels.iter())
-        .for_each(|(kernel, adjustment)| *kernel -= adjustment);
-    *biases -= &next_layer_derivatives.scale(LEARNING_RATE);
 
-    prev_layer_derivatives
+    (kernel_derivatives, next_layer_derivatives, prev_layer_derivatives)
 }
 
-impl Network {
-    fn get_save_data(&self) -> Vec<f64> {
-        let mut data = Vec::with_capacity(NETWORK_SIZE);
+/// `bp_convolution` for every example of a batch.
+/// The derivatives for the kernels and biases are summed over the batch.
+fn bp_convolution_batch<const K: usize, const A: usize, const B: usize>(
+    kernels: &[Tensor3<f64, K, K, A>; B],
+    prev_activations: &[Tensor3<f64, 5, 5, A>],
+    next_layer_derivatives: &[Tensor3<f64, 5, 5, B>],
+) -> ([Tensor3<f64, K, K, A>; B], Tensor3<f64, 5, 5, B>, Vec<Tensor3<f64, 5, 5, A>>)
+where
+    [(); K * K * A]: ,
+    [(); 5 * 5 * A]: ,
+    [(); 5 * 5 * B]: ,
+{
+    let mut kernel_derivatives = [Tensor3::default(); B];
+    let mut bias_derivatives = Tensor3::default();
+    let mut prev_layer_derivatives = Vec::with_capacity(prev_activations.len());
+    for (&prev, &next) in prev_activations.iter().zip(next_layer_derivatives.iter()) {
+        let (kernels_d, biases_d, prev_d) = bp_convolution(kernels, prev, next);
+        for (sum, d) in kernel_derivatives.iter_mut().zip(kernels_d.iter()) {
+            *sum += d;
+        }
+        bias_derivatives += &biases_d;
+        prev_layer_derivatives.push(prev_d);
+    }
 
-        // Convolutional layers.
-        for tensor in self.l1_kernels.iter() {
-            data.extend(tensor.iter());
+    (kernel_derivatives, bias_derivatives, prev_layer_derivatives)
+}
+
+impl<const C: usize> Network<C>
+where
+    [(); 3 * 3 * C]: ,
+    [(); 5 * 5 * C]: ,
+    [(); 1 * 1 * C]: ,
+{
+    /// The parameters followed by the running statistics of batch
+    /// normalization.
+    fn get_save_data(&self) -> Vec<f64> {
+        let mut data: Vec<f64> = self.params().into_iter().flatten().copied().collect();
+        for norm in self.norms() {
+            data.extend(norm.running.mean.iter().chain(norm.running.variance.iter()));
         }
-        data.extend(self.l1_biases.iter());
-        for tensor in self.l2_kernels.iter() {
-            data.extend(tensor.iter());
+        data
+    }
+
+    fn from_save_data(blocks: usize, data: Vec<f64>) -> Network<C> {
+        // The random weights are all overwritten.
+        let mut network = Network::init(blocks);
+        let mut iter = data.into_iter();
+        for params in network.params_mut() {
+            for param in params.iter_mut() {
+                *param = iter.next().expect("not enough weights for the network");
+            }
         }
-        data.extend(self.l2_biases.iter());
-        for tensor in self.l3_kernels.iter() {
-            data.extend(tensor.iter());
+        for norm in network.norms_mut() {
+            let running = &mut norm.running;
+            let statistics = running.mean.get_data_mut().iter_mut();
+            for statistic in statistics.chain(running.variance.get_data_mut().iter_mut()) {
+                *statistic = iter.next().expect("not enough weights for the network");
+            }
         }
-        data.extend(self.l3_biases.iter());
-        for tensor in self.l4_kernels.iter() {
-            data.extend(tensor.iter());
+        assert!(iter.next().is_none(), "too many weights for the network");
+        network
+    }
+
+    /// Names and shapes of the tensors in `get_save_data` of a network with
+    /// `blocks` residual blocks, in order. The shapes are the dimensions of
+    /// the Rust types, with arrays of tensors first.
+    fn layout(blocks: usize) -> Vec<(String, Vec<usize>)> {
+        let norm = |prefix: &str| {
+            vec![
+                (format!("{}.gamma", prefix), vec![C]),
+                (format!("{}.beta", prefix), vec![C]),
+            ]
+        };
+        let mut layout = vec![
+            ("input.kernels".to_string(), vec![C, 3, 3, 8]),
+            ("input.biases".to_string(), vec![5, 5, C]),
+        ];
+        layout.extend(norm("input.norm"));
+        for block in 0..blocks {
+            for conv in 1..=2 {
+                layout.push((format!("tower.{}.kernels{}", block, conv), vec![C, 3, 3, C]));
+                layout.push((format!("tower.{}.biases{}", block, conv), vec![5, 5, C]));
+                layout.extend(norm(&format!("tower.{}.norm{}", block, conv)));
+            }
         }
-        data.extend(self.l4_biases.iter());
-        // Fully connected layers.
-        for tensor in self.l5_weights.iter() {
-            data.extend(tensor.iter());
+        layout.extend(vec![
+            ("policy.kernels".to_string(), vec![2, 1, 1, C]),
+            ("policy.kernel_biases".to_string(), vec![5, 5, 2]),
+            ("policy.weights".to_string(), vec![POLICY_SIZE, 50]),
+            ("policy.biases".to_string(), vec![POLICY_SIZE]),
+            ("value.kernels".to_string(), vec![1, 1, 1, C]),
+            ("value.kernel_biases".to_string(), vec![5, 5, 1]),
+            ("value.hidden_weights".to_string(), vec![VALUE_HIDDEN, 25]),
+            ("value.hidden_biases".to_string(), vec![VALUE_HIDDEN]),
+            ("value.weights".to_string(), vec![1, VALUE_HIDDEN]),
+            ("value.biases".to_string(), vec![1]),
+        ]);
+        // The running statistics come after all of the parameters.
+        let mut norms = vec!["input.norm".to_string()];
+        for block in 0..blocks {
+            norms.push(format!("tower.{}.norm1", block));
+            norms.push(format!("tower.{}.norm2", block));
         }
-        data.extend(self.l5_biases.iter());
-        for tensor in self.l6_weights.iter() {
-            data.extend(tensor.iter());
+        for norm in norms {
+            layout.push((format!("{}.running_mean", norm), vec![C]));
+            layout.push((format!("{}.running_variance", norm), vec![C]));
         }
-        data.extend(self.l6_biases.iter());
+        layout
+    }
 
-        data
+    /// The data of `get_save_data` split into the tensors of `layout`.
+    pub fn named_tensors(&self) -> Vec<NamedTensor> {
+        let mut data = self.get_save_data().into_iter();
+        Network::<C>::layout(self.blocks())
+            .into_iter()
+            .map(|(name, shape)| NamedTensor {
+                data: data.by_ref().take(shape.iter().product()).collect(),
+                name,
+                shape,
+            })
+            .collect()
     }
 
-    fn from_save_data(data: Vec<f64>) -> Network {
-        assert_eq!(data.len(), NETWORK_SIZE);
-        let mut iter = data.into_iter();
-        Network {
-            fft_planner: Arc::new(FftPlanner::new()),
-            // Padded convolution layers.
-            l1_kernels: [(); 64].map(|()| Tensor3::new([(); 3 * 3 * 8].map(|()| iter.next().unwrap()))),
-            l1_biases: Tensor3::new([(); 5 * 5 * 64].map(|()| iter.next().unwrap())),
-            l2_kernels: [(); 64].map(|()| Tensor3::new([(); 3 * 3 * 64].map(|()| iter.next().unwrap()))),
-            l2_biases: Tensor3::new([(); 5 * 5 * 64].map(|()| iter.next().unwrap())),
-            l3_kernels: [(); 64].map(|()| Tensor3::new([(); 3 * 3 * 64].map(|()| iter.next().unwrap()))),
-            l3_biases: Tensor3::new([(); 5 * 5 * 64].map(|()| iter.next().unwrap())),
-            l4_kernels: [(); 64].map(|()| Tensor3::new([(); 3 * 3 * 64].map(|()| iter.next().unwrap()))),
-            l4_biases: Tensor3::new([(); 5 * 5 * 64].map(|()| iter.next().unwrap())),
-            // Fully connected layers.
-            l5_weights: [(); 800].map(|()| Tensor1::new([(); 1600].map(|()| iter.next().unwrap()))),
-            l5_biases: Tensor1::new([(); 800].map(|()| iter.next().unwrap())),
-            l6_weights: [(); 626].map(|()| Tensor1::new([(); 800].map(|()| iter.next().unwrap()))),
-            l6_biases: Tensor1::new([(); 626].map(|()| iter.next().unwrap())),
+    pub fn architecture(&self) -> Architecture {
+        Architecture {
+            channels: C,
+            blocks: self.blocks(),
+            policy_size: POLICY_SIZE,
+            value_hidden: VALUE_HIDDEN,
         }
     }
 
+    /// Save the weights as named tensors together with the architecture,
+    /// so that incompatible networks are not mixed up.
     pub fn save(&self, path: &str) {
-        let data = bincode::serialize(&self.get_save_data()).unwrap();
-        fs::write(path, data).expect("couldn't save network to file");
+        fs::write(path, model_file::encode(&self.architecture(), &self.named_tensors()))
+            .expect("couldn't save network to file");
+    }
+
+    /// Export the network for inference in other tools, see `onnx::export`.
+    pub fn save_onnx(&self, path: &str) {
+        fs::write(path, onnx::export(&self.architecture(), &self.named_tensors()))
+            .expect("couldn't save ONNX model to file");
+    }
+
+    /// Load a network saved with `save`, or in one of the older formats
+    /// which can be converted.
+    pub fn load(path: &str) -> Result<Network<C>, LoadError> {
+        let data = fs::read(path)?;
+        let (architecture, tensors) = if model_file::has_magic(&data) {
+            model_file::decode(&data)?
+        } else {
+            Network::<C>::migrate(&data)?
+        };
+        if tensors.first().map_or(false, |tensor| tensor.name == LEGACY_FIRST_TENSOR) {
+            return Err(LoadError::Obsolete);
+        }
+        Network::from_tensors(architecture, tensors)
+    }
+
+    fn from_tensors(architecture: Architecture, tensors: Vec<NamedTensor>) -> Result<Network<C>, LoadError> {
+        check_architecture::<C>(&architecture)?;
+        let mut tensors = tensors.into_iter();
+        let mut data = Vec::new();
+        for (name, shape) in Network::<C>::layout(architecture.blocks) {
+            let tensor = tensors.next().ok_or_else(|| LoadError::TensorMismatch {
+                expected: name.clone(),
+                found: "end of file".to_string(),
+            })?;
+            if tensor.name != name {
+                return Err(LoadError::TensorMismatch {
+                    expected: name,
+                    found: tensor.name,
+                });
+            }
+            if tensor.shape != shape {
+                return Err(LoadError::ShapeMismatch {
+                    name,
+                    saved: tensor.shape,
+                    expected: shape,
+                });
+            }
+            data.extend(tensor.data);
+        }
+        if let Some(tensor) = tensors.next() {
+            return Err(LoadError::TensorMismatch {
+                expected: "end of file".to_string(),
+                found: tensor.name,
+            });
+        }
+        Ok(Network::from_save_data(architecture.blocks, data))
     }
 
-    pub fn load(path: &str) -> Network {
-        let data = fs::read(path).expect("couldn't read file");
-        Network::from_save_data(bincode::deserialize(&data).unwrap())
+    /// Convert a file from before the current format. Versions 2 and 3 had
+    /// a bincode header with the version, the policy size, the channels and
+    /// the blocks, followed by the data of `get_save_data`. Version 2 had no
+    /// batch normalization, which becomes the identity. Older files, version
+    /// 1 and the ones without any header, were saved for the network before
+    /// the residual tower and can only be converted with `convert_legacy`.
+    fn migrate(data: &[u8]) -> Result<(Architecture, Vec<NamedTensor>), LoadError> {
+        let version: u32 = bincode::deserialize(data)?;
+        if version != 2 && version != 3 {
+            return Err(match read_legacy(data) {
+                Some(_) => LoadError::Obsolete,
+                None => LoadError::NotAModel,
+            });
+        }
+        let ((_, policy_size, channels, blocks), weights): ((u32, u32, u32, u32), Vec<f64>) =
+            bincode::deserialize(data)?;
+        let architecture = Architecture {
+            channels: channels as usize,
+            blocks: blocks as usize,
+            policy_size: policy_size as usize,
+            value_hidden: VALUE_HIDDEN,
+        };
+        // The layout depends on the architecture.
+        check_architecture::<C>(&architecture)?;
+        let mut weights = weights.into_iter();
+        let mut tensors = Vec::new();
+        for (name, shape) in Network::<C>::layout(architecture.blocks) {
+            let len = shape.iter().product();
+            let data = if version == 2 && name.contains("norm") {
+                let identity = name.ends_with(".gamma") || name.ends_with(".running_variance");
+                vec![if identity { 1. } else { 0. }; len]
+            } else {
+                weights.by_ref().take(len).collect()
+            };
+            if data.len() != len {
+                return Err(LoadError::Malformed("not enough weights for the network".to_string()));
+            }
+            tensors.push(NamedTensor { name, shape, data });
+        }
+        if weights.next().is_some() {
+            return Err(LoadError::Malformed("too many weights for the network".to_string()));
+        }
+        Ok((architecture, tensors))
     }
 }
 
+/// Check that a saved architecture fits a network with `C` channels.
+/// Any number of blocks fits.
+fn check_architecture<const C: usize>(architecture: &Architecture) -> Result<(), LoadError> {
+    let fields = [
+        ("channels", architecture.channels, C),
+        ("policy size", architecture.policy_size, POLICY_SIZE),
+        ("value hidden units", architecture.value_hidden, VALUE_HIDDEN),
+    ];
+    for &(field, saved, expected) in fields.iter() {
+        if saved != expected {
+            return Err(LoadError::Architecture {
+                field,
+                saved,
+                expected,
+            });
+        }
+    }
+    Ok(())
+}
+
+const LEGACY_FIRST_TENSOR: &str = "l1.kernels";
+const LEGACY_CHANNELS: usize = 64;
+const LEGACY_HIDDEN: usize = 800;
+
+/// Tensors of the network before the residual tower: four padded 3x3
+/// convolutions `l1` to `l4` with 64 channels and per position biases, a fully
+/// connected layer `l5` with 800 units, and the output layer `l6` with the
+/// policy followed by one output for the value. All layers but the output use
+/// relu, the value goes through tanh.
+pub fn legacy_layout(policy_size: usize) -> Vec<(String, Vec<usize>)> {
+    let mut layout = Vec::new();
+    for (layer, inputs) in [(1, 8), (2, LEGACY_CHANNELS), (3, LEGACY_CHANNELS), (4, LEGACY_CHANNELS)].iter() {
+        layout.push((format!("l{}.kernels", layer), vec![LEGACY_CHANNELS, 3, 3, *inputs]));
+        layout.push((format!("l{}.biases", layer), vec![5, 5, LEGACY_CHANNELS]));
+    }
+    layout.push(("l5.weights".to_string(), vec![LEGACY_HIDDEN, 25 * LEGACY_CHANNELS]));
+    layout.push(("l5.biases".to_string(), vec![LEGACY_HIDDEN]));
+    layout.push(("l6.weights".to_string(), vec![policy_size + 1, LEGACY_HIDDEN]));
+    layout.push(("l6.biases".to_string(), vec![policy_size + 1]));
+    layout
+}
+
+/// Policy size and weights of a file saved for the network before the
+/// residual tower. Version 1 had a header with the version and the policy
+/// size, files without a header were all for the `Squares` action space.
+fn read_legacy(data: &[u8]) -> Option<(usize, Vec<f64>)> {
+    // A headerless file starts with the length of its weights.
+    let len: u64 = bincode::deserialize(data).ok()?;
+    let headerless = len.checked_mul(8).and_then(|bytes| bytes.checked_add(8)) == Some(data.len() as u64);
+    let (policy_size, weights) = if headerless {
+        (ActionSpace::Squares.size(), bincode::deserialize(data).ok()?)
+    } else {
+        let (version, policy_size, weights): (u32, u32, Vec<f64>) = bincode::deserialize(data).ok()?;
+        if version != 1 {
+            return None;
+        }
+        (policy_size as usize, weights)
+    };
+    let expected: usize = legacy_layout(policy_size)
+        .iter()
+        .map(|(_, shape)| shape.iter().product::<usize>())
+        .sum();
+    if weights.len() != expected {
+        return None;
+    }
+    Some((policy_size, weights))
+}
+
+/// Convert a file saved for the network before the residual tower into the
+/// current format, with the tensors of `legacy_layout`. The weights are kept
+/// for other tools, `Network::load` still rejects them as obsolete.
+pub fn convert_legacy(data: &[u8]) -> Result<Vec<u8>, LoadError> {
+    let (policy_size, weights) = read_legacy(data).ok_or(LoadError::NotAModel)?;
+    let architecture = Architecture {
+        channels: LEGACY_CHANNELS,
+        blocks: 0,
+        policy_size,
+        value_hidden: LEGACY_HIDDEN,
+    };
+    let mut weights = weights.into_iter();
+    let tensors: Vec<_> = legacy_layout(policy_size)
+        .into_iter()
+        .map(|(name, shape)| NamedTensor {
+            data: weights.by_ref().take(shape.iter().product()).collect(),
+            name,
+            shape,
+        })
+        .collect();
+    Ok(model_file::encode(&architecture, &tensors))
+}
+
+/// Save the state of an optimizer, to be stored next to the network.
+pub fn save_optimizer<O: Optimizer>(optimizer: &O, path: &str) {
+    let data = bincode::serialize(&optimizer.get_save_data()).unwrap();
+    fs::write(path, data).expect("couldn't save optimizer to file");
+}
+
+/// Restore the state of an optimizer saved with `save_optimizer`.
+pub fn load_optimizer<O: Optimizer>(optimizer: &mut O, path: &str) {
+    let data = fs::read(path).expect("couldn't read file");
+    optimizer.load_save_data(bincode::deserialize(&data).unwrap());
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::rand_game::random_game;
+
+    /// Small network which is quick to test.
+    fn small_network() -> Network<4> {
+        Network::init(2)
+    }
+
+    /// Random training examples for `small_network`.
+    fn random_batch(
+        size: usize,
+    ) -> Vec<(Tensor3<f64, 5, 5, 8>, [bool; POLICY_SIZE], Tensor1<f64, POLICY_SIZE>, f64)> {
+        (0..size)
+            .map(|_| {
+                (
+                    Tensor3::rand(rand_distr::Uniform::new(0., 1.)),
+                    legal_mask(&random_game()),
+                    Tensor1::rand(rand_distr::Uniform::new(0., 1.)),
+                    0.5,
+                )
+            })
+            .collect()
+    }
 
     #[test]
     fn save_and_load() {
         with_larger_stack(|| {
-            let orig = Network::init();
+            let mut orig = small_network();
+            // Training moves the running statistics, which are saved as well.
+            let input = Tensor3::rand(rand_distr::Uniform::new(0., 1.));
+            let pi = Tensor1::rand(rand_distr::Uniform::new(0., 1.));
+            orig.back_prop(input, &legal_mask(&random_game()), pi, 0.5, &mut Sgd::new(0.1), 0.);
+            assert_ne!(orig.input_norm.running, BatchNorm::default().running);
             orig.save("test.data");
-            let network = Network::load("test.data");
+            let network = Network::<4>::load("test.data").unwrap();
             fs::remove_file("test.data").unwrap();
-            assert_eq!(orig.l1_kernels, network.l1_kernels);
-            assert_eq!(orig.l1_biases, network.l1_biases);
-            assert_eq!(orig.l2_kernels, network.l2_kernels);
-            assert_eq!(orig.l2_biases, network.l2_biases);
-            assert_eq!(orig.l3_kernels, network.l3_kernels);
-            assert_eq!(orig.l3_biases, network.l3_biases);
-            assert_eq!(orig.l4_kernels, network.l4_kernels);
-            assert_eq!(orig.l4_biases, network.l4_biases);
-            assert_eq!(orig.l5_weights, network.l5_weights);
-            assert_eq!(orig.l5_biases, network.l5_biases);
-            assert_eq!(orig.l6_weights, network.l6_weights);
-            assert_eq!(orig.l6_biases, network.l6_biases);
+            assert_eq!(network.blocks(), 2);
+            assert_eq!(orig.get_save_data(), network.get_save_data());
+        })
+    }
+
+    #[test]
+    fn layout_matches_save_data() {
+        with_larger_stack(|| {
+            let network = small_network();
+            let layout = Network::<4>::layout(network.blocks());
+            let len: usize = layout.iter().map(|(_, shape)| shape.iter().product::<usize>()).sum();
+            assert_eq!(len, network.get_save_data().len());
+        })
+    }
+
+    #[test]
+    fn reject_mismatched_tensors() {
+        with_larger_stack(|| {
+            let network = small_network();
+            let tensors = || network.named_tensors();
+            let mut architecture = network.architecture();
+            architecture.value_hidden += 1;
+            let result = Network::<4>::from_tensors(architecture, tensors());
+            assert!(matches!(result, Err(LoadError::Architecture { field: "value hidden units", .. })));
+            let mut renamed = tensors();
+            renamed[3].name = "input.norm.bias".to_string();
+            let result = Network::<4>::from_tensors(network.architecture(), renamed);
+            assert!(matches!(result, Err(LoadError::TensorMismatch { .. })));
+            let mut truncated = tensors();
+            truncated.pop();
+            let result = Network::<4>::from_tensors(network.architecture(), truncated);
+            assert!(matches!(result, Err(LoadError::TensorMismatch { .. })));
+        })
+    }
+
+    #[test]
+    fn migrate_version_3() {
+        with_larger_stack(|| {
+            let mut orig = small_network();
+            let batch = random_batch(2);
+            orig.back_prop_batch(&batch, &mut Sgd::new(0.1), 0.);
+            let header = (3u32, POLICY_SIZE as u32, 4u32, 2u32);
+            let data = bincode::serialize(&(header, orig.get_save_data())).unwrap();
+            let (architecture, tensors) = Network::<4>::migrate(&data).unwrap();
+            let network = Network::<4>::from_tensors(architecture, tensors).unwrap();
+            assert_eq!(orig.get_save_data(), network.get_save_data());
+        })
+    }
+
+    #[test]
+    fn migrate_version_2() {
+        with_larger_stack(|| {
+            // A new network has batch normalization which is the identity.
+            let orig = small_network();
+            let mut data = orig.get_save_data().into_iter();
+            let mut weights = Vec::new();
+            for (name, shape) in Network::<4>::layout(orig.blocks()) {
+                let tensor: Vec<_> = data.by_ref().take(shape.iter().product()).collect();
+                if !name.contains("norm") {
+                    weights.extend(tensor);
+                }
+            }
+            let header = (2u32, POLICY_SIZE as u32, 4u32, 2u32);
+            let data = bincode::serialize(&(header, weights)).unwrap();
+            let (architecture, tensors) = Network::<4>::migrate(&data).unwrap();
+            let network = Network::<4>::from_tensors(architecture, tensors).unwrap();
+            assert_eq!(orig.get_save_data(), network.get_save_data());
+        })
+    }
+
+    #[test]
+    fn reject_obsolete_files() {
+        let legacy_size = |policy_size| -> usize {
+            legacy_layout(policy_size).iter().map(|(_, shape)| shape.iter().product::<usize>()).sum()
+        };
+        let headerless = bincode::serialize(&vec![0.; legacy_size(625)]).unwrap();
+        assert!(matches!(Network::<4>::migrate(&headerless), Err(LoadError::Obsolete)));
+        let version_1 = bincode::serialize(&(1u32, 1250u32, vec![0.; legacy_size(1250)])).unwrap();
+        assert!(matches!(Network::<4>::migrate(&version_1), Err(LoadError::Obsolete)));
+        let truncated = bincode::serialize(&vec![0.; 10]).unwrap();
+        assert!(matches!(Network::<4>::migrate(&truncated), Err(LoadError::NotAModel)));
+        let other = bincode::serialize(&(7u32, 0u32)).unwrap();
+        assert!(matches!(Network::<4>::migrate(&other), Err(LoadError::NotAModel)));
+    }
+
+    #[test]
+    fn convert_legacy_files() {
+        let layout = legacy_layout(625);
+        let len = layout.iter().map(|(_, shape)| shape.iter().product::<usize>()).sum();
+        let weights: Vec<f64> = (0..len).map(|i| i as f64).collect();
+        let converted = convert_legacy(&bincode::serialize(&weights).unwrap()).unwrap();
+        let (architecture, tensors) = model_file::decode(&converted).unwrap();
+        assert_eq!((architecture.blocks, architecture.policy_size), (0, 625));
+        let names: Vec<_> = tensors
+            .iter()
+            .map(|tensor| (tensor.name.clone(), tensor.shape.clone()))
+            .collect();
+        assert_eq!(names, layout);
+        assert_eq!(tensors.into_iter().flat_map(|tensor| tensor.data).collect::<Vec<_>>(), weights);
+
+        // The converted file is recognized, but does not fit the network.
+        fs::write("test_legacy.data", converted).unwrap();
+        let loaded = Network::<4>::load("test_legacy.data");
+        fs::remove_file("test_legacy.data").unwrap();
+        assert!(matches!(loaded, Err(LoadError::Obsolete)));
+    }
+
+    #[test]
+    fn feed_forward_batch() {
+        with_larger_stack(|| {
+            let network = small_network();
+            let inputs: Vec<_> = (0..3)
+                .map(|_| Tensor3::rand(rand_distr::Uniform::new(0., 1.)))
+                .collect();
+            let masks: Vec<_> = (0..3).map(|_| legal_mask(&random_game())).collect();
+            let batch = network.feed_forward_batch(&inputs, &masks);
+            for ((&input, mask), (policy, eval)) in inputs.iter().zip(masks.iter()).zip(batch.into_iter()) {
+                let (single_policy, single_eval) = network.feed_forward(input, mask);
+                assert!((single_policy - &policy).map(f64::abs).sum() < 1e-6);
+                assert!((single_eval - eval).abs() < 1e-6);
+                // All of the policy goes to legal moves.
+                for (p, &legal) in policy.iter().zip(mask.iter()) {
+                    assert!(legal || *p == 0.);
+                }
+                assert!((policy.sum() - 1.).abs() < 1e-9);
+            }
+        })
+    }
+
+    #[test]
+    fn initial_activations_are_bounded() {
+        with_larger_stack(|| {
+            let network = Network::<16>::init(4);
+            let inputs: Vec<_> = (0..8)
+                .map(|_| Tensor3::rand(rand_distr::Uniform::new(0., 1.)))
+                .collect();
+            let fft_planner = &mut FftPlanner::new();
+            let (_, trace) = network.feed_forward_tower(&inputs, BatchNormMode::Inference, fft_planner);
+            let variance = |xs: &[Tensor3<f64, 5, 5, 16>]| {
+                let n = (xs.len() * 5 * 5 * 16) as f64;
+                let mean = xs.iter().map(|x| x.sum()).sum::<f64>() / n;
+                xs.iter().map(|x| x.map(|x| (x - mean).powi(2)).sum()).sum::<f64>() / n
+            };
+            let mut variances = vec![variance(&trace.input_x)];
+            for block in trace.blocks.iter() {
+                variances.push(variance(&block.hidden));
+                variances.push(variance(&block.output));
+            }
+            // Positive weights would multiply the variance by the fan-in of
+            // every layer instead.
+            for variance in variances {
+                assert!(0.01 < variance && variance < 100., "variance {}", variance);
+            }
+            let masks = vec![[true; POLICY_SIZE]; inputs.len()];
+            for (policy, eval) in network.feed_forward_batch(&inputs, &masks) {
+                assert!(policy.iter().all(|&p| p < 0.5));
+                // Saturated evaluations are rounded to exactly 1.
+                assert!(eval.abs() < 1.);
+            }
+        })
+    }
+
+    #[test]
+    fn gradients_then_apply() {
+        with_larger_stack(|| {
+            let orig = small_network();
+            let batch = random_batch(2);
+            let (gradients, _) = orig.gradients(&batch);
+            let mut applied = orig.clone();
+            applied.apply(&gradients, &mut Sgd::new(0.1));
+            let mut back_propped = orig.clone();
+            back_propped.back_prop_batch(&batch, &mut Sgd::new(0.1), 0.);
+            assert_eq!(applied.get_save_data(), back_propped.get_save_data());
+        })
+    }
+
+    #[test]
+    fn gradients_match_finite_differences() {
+        with_larger_stack(|| {
+            let mut network = small_network();
+            // Batch normalization ties the examples of a batch together.
+            let batch = random_batch(2);
+            let loss = |network: &Network<4>| {
+                let (_, loss) = network.gradients(&batch);
+                (loss.value + loss.policy) / batch.len() as f64
+            };
+            let (gradients, _) = network.gradients(&batch);
+            let gradients: Vec<f64> = gradients.params().iter().map(|grads| grads[0]).collect();
+            // Check the first weight of every tensor, which includes every
+            // convolution and batch normalization in the tower and so the
+            // skip connections.
+            let epsilon = 1e-6;
+            for (i, gradient) in gradients.into_iter().enumerate() {
+                network.params_mut()[i][0] += epsilon;
+                let above = loss(&network);
+                network.params_mut()[i][0] -= 2. * epsilon;
+                let below = loss(&network);
+                network.params_mut()[i][0] += epsilon;
+                let estimate = (above - below) / (2. * epsilon);
+                assert!((estimate - gradient).abs() < 1e-4 * (1. + gradient.abs()));
+            }
         })
     }
+
+    #[test]
+    fn illegal_moves_are_ignored() {
+        with_larger_stack(|| {
+            let network = small_network();
+            let input = Tensor3::rand(rand_distr::Uniform::new(0., 1.));
+            let mask = legal_mask(&random_game());
+            let pi = Tensor1::rand(rand_distr::Uniform::new(0., 1.));
+            let mut other_pi = pi;
+            for (target, &legal) in other_pi.get_data_mut().iter_mut().zip(mask.iter()) {
+                if !legal {
+                    *target = 1.;
+                }
+            }
+            let (gradients, loss) = network.gradients(&[(input, mask, pi, 0.5)]);
+            let (other_gradients, other_loss) = network.gradients(&[(input, mask, other_pi, 0.5)]);
+            assert_eq!(loss.policy, other_loss.policy);
+            assert!(loss.policy.is_finite());
+            for (grads, other_grads) in gradients.params().into_iter().zip(other_gradients.params()) {
+                assert_eq!(grads, other_grads);
+            }
+        })
+    }
+
+    #[test]
+    fn weight_decay() {
+        with_larger_stack(|| {
+            let network = small_network();
+            let mut gradients = Gradients::zero(network.blocks());
+            let penalty = network.weight_decay(&mut gradients, 0.5);
+            let expected: f64 = network.params().into_iter().flatten().map(|w| 0.5 * w * w).sum();
+            assert!((penalty - expected).abs() < 1e-6 * expected);
+            for (params, grads) in network.params().into_iter().zip(gradients.params().into_iter()) {
+                assert_eq!(params, grads);
+            }
+        })
+    }
+
+    #[test]
+    fn evaluate_uses_cache() {
+        with_larger_stack(|| {
+            let mut network = small_network();
+            let game = random_game();
+            let games = [game, game.forward().next().unwrap()];
+            let eval = network.evaluate(&game);
+            assert_eq!(network.evaluate(&game), eval);
+            let evals = network.evaluate_batch(&games);
+            assert_eq!(evals[0], eval);
+            let stats = network.cache_stats();
+            assert_eq!((stats.hits, stats.misses), (2, 2));
+            // New weights make the cached evaluations stale.
+            network.apply(&Gradients::zero(network.blocks()), &mut Sgd::new(0.1));
+            network.evaluate(&game);
+            assert_eq!(network.cache_stats().misses, 3);
+        })
+    }
+
+    #[test]
+    fn hit_rate_without_lookups() {
+        assert_eq!(CacheStats::default().hit_rate(), 0.);
+        let mut stats = CacheStats { hits: 1, misses: 0 };
+        stats += CacheStats { hits: 0, misses: 3 };
+        assert_eq!(stats.hit_rate(), 0.25);
+    }
 }
 
 #[cfg(test)]
@@ -321,31 +1635,47 @@ mod benches {
     use test::Bencher;
 
     use super::*;
+    use crate::alpha_zero::{BLOCKS, CHANNELS};
 
     #[bench]
     fn init(ben: &mut Bencher) {
         with_larger_stack(move || {
-            ben.iter(|| Network::init());
+            ben.iter(|| Network::<CHANNELS>::init(BLOCKS));
         });
     }
 
     #[bench]
     fn forward_pass(ben: &mut Bencher) {
         with_larger_stack(move || {
-            let network = Network::init();
+            let network = Network::<CHANNELS>::init(BLOCKS);
             let input = Tensor3::rand(rand_distr::Uniform::new(0., 1.));
-            ben.iter(|| network.feed_forward(input));
+            let mask = [true; POLICY_SIZE];
+            ben.iter(|| network.feed_forward(input, &mask));
+        })
+    }
+
+    #[bench]
+    fn forward_pass_batch(ben: &mut Bencher) {
+        with_larger_stack(move || {
+            let network = Network::<CHANNELS>::init(BLOCKS);
+            let inputs: Vec<_> = (0..16)
+                .map(|_| Tensor3::rand(rand_distr::Uniform::new(0., 1.)))
+                .collect();
+            let masks = vec![[true; POLICY_SIZE]; inputs.len()];
+            ben.iter(|| network.feed_forward_batch(&inputs, &masks));
         })
     }
 
     #[bench]
     fn back_prop(ben: &mut Bencher) {
         with_larger_stack(move || {
-            let mut network = Network::init();
+            let mut network = Network::<CHANNELS>::init(BLOCKS);
             let input = Tensor3::rand(rand_distr::Uniform::new(0., 1.));
             let pi = Tensor1::rand(rand_distr::Uniform::new(0., 1.));
             let z = 0.5;
-            ben.iter(|| network.back_prop(input, pi, z));
+            let mut optimizer = Sgd::new(0.0001);
+            let mask = [true; POLICY_SIZE];
+            ben.iter(|| network.back_prop(input, &mask, pi, z, &mut optimizer, 0.0001));
         })
     }
 }
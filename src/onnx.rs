@@ -0,0 +1,436 @@
+use tensor::BATCH_NORM_EPSILON;
+
+use crate::model_file::{Architecture, NamedTensor};
+
+const IR_VERSION: i64 = 7;
+const OPSET_VERSION: i64 = 13;
+// TensorProto.DataType
+const FLOAT: i64 = 1;
+// AttributeProto.AttributeType
+const ATTRIBUTE_FLOAT: i64 = 1;
+const ATTRIBUTE_INT: i64 = 2;
+const ATTRIBUTE_INTS: i64 = 7;
+/// Added to the logits of illegal moves, so that the softmax gives them zero.
+const ILLEGAL_LOGIT: f64 = -1e9;
+
+/// Protocol buffer message being encoded.
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn raw_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u64, wire_type: u64) {
+        self.raw_varint(field << 3 | wire_type);
+    }
+
+    fn int(&mut self, field: u64, value: i64) -> &mut Message {
+        self.key(field, 0);
+        self.raw_varint(value as u64);
+        self
+    }
+
+    fn float(&mut self, field: u64, value: f32) -> &mut Message {
+        self.key(field, 5);
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn bytes(&mut self, field: u64, data: &[u8]) -> &mut Message {
+        self.key(field, 2);
+        self.raw_varint(data.len() as u64);
+        self.0.extend_from_slice(data);
+        self
+    }
+
+    fn string(&mut self, field: u64, value: &str) -> &mut Message {
+        self.bytes(field, value.as_bytes())
+    }
+
+    fn message(&mut self, field: u64, message: &Message) -> &mut Message {
+        self.bytes(field, &message.0)
+    }
+}
+
+// The field numbers below are from onnx.proto.
+
+fn int_attribute(name: &str, value: i64) -> Message {
+    let mut attribute = Message::default();
+    attribute.string(1, name).int(20, ATTRIBUTE_INT).int(3, value);
+    attribute
+}
+
+fn ints_attribute(name: &str, values: &[i64]) -> Message {
+    let mut attribute = Message::default();
+    attribute.string(1, name).int(20, ATTRIBUTE_INTS);
+    for &value in values {
+        attribute.int(8, value);
+    }
+    attribute
+}
+
+fn float_attribute(name: &str, value: f64) -> Message {
+    let mut attribute = Message::default();
+    attribute.string(1, name).int(20, ATTRIBUTE_FLOAT).float(2, value as f32);
+    attribute
+}
+
+/// Tensor of 32-bit floats, stored as raw little-endian data.
+fn tensor(name: &str, dims: &[usize], data: &[f64]) -> Message {
+    let mut raw = Vec::with_capacity(4 * data.len());
+    for &value in data {
+        raw.extend_from_slice(&(value as f32).to_le_bytes());
+    }
+    let mut tensor = Message::default();
+    for &dim in dims {
+        tensor.int(1, dim as i64);
+    }
+    tensor.int(2, FLOAT).string(8, name).bytes(9, &raw);
+    tensor
+}
+
+/// Input or output of the graph. The first dimension is the batch, which can
+/// have any size.
+fn value_info(name: &str, dims: &[usize]) -> Message {
+    let mut shape = Message::default();
+    shape.message(1, Message::default().string(2, "batch"));
+    for &dim in dims {
+        shape.message(1, Message::default().int(1, dim as i64));
+    }
+    let mut tensor_type = Message::default();
+    tensor_type.int(1, FLOAT).message(2, &shape);
+    let mut value_info = Message::default();
+    value_info.string(1, name).message(2, Message::default().message(1, &tensor_type));
+    value_info
+}
+
+/// Shape in ONNX of a tensor from `Network::layout`, keeping the order of the
+/// values. Tensors in the network are stored with the first dimension
+/// changing fastest, so kernels `[out, D1, D2, in]` become `[out, in, D2, D1]`
+/// and the biases of convolutions `[D1, D2, channels]` become
+/// `[1, channels, D2, D1]`, which adds them to every example of a batch.
+fn onnx_dims(shape: &[usize]) -> Vec<usize> {
+    match *shape {
+        [out, d1, d2, channels] => vec![out, channels, d2, d1],
+        [d1, d2, channels] => vec![1, channels, d2, d1],
+        _ => shape.to_vec(),
+    }
+}
+
+#[derive(Default)]
+struct Graph {
+    nodes: Vec<Message>,
+    initializers: Vec<Message>,
+}
+
+impl Graph {
+    /// Add a node whose single output is called `output`, which is returned.
+    fn node(&mut self, op_type: &str, inputs: &[&str], output: &str, attributes: &[Message]) -> String {
+        let mut node = Message::default();
+        for input in inputs {
+            node.string(1, input);
+        }
+        node.string(2, output).string(3, output).string(4, op_type);
+        for attribute in attributes {
+            node.message(5, attribute);
+        }
+        self.nodes.push(node);
+        output.to_string()
+    }
+
+    fn initializer(&mut self, name: &str, dims: &[usize], data: &[f64]) {
+        self.initializers.push(tensor(name, dims, data));
+    }
+
+    /// Padded convolution with biases for every position, like
+    /// `convolution_pass`.
+    fn convolution(
+        &mut self,
+        input: &str,
+        kernels: &str,
+        biases: &str,
+        kernel_size: usize,
+        output: &str,
+    ) -> String {
+        let pad = (kernel_size / 2) as i64;
+        let attributes = [
+            ints_attribute("kernel_shape", &[kernel_size as i64; 2]),
+            ints_attribute("pads", &[pad; 4]),
+        ];
+        let x = self.node("Conv", &[input, kernels], &format!("{}.unbiased", output), &attributes);
+        self.node("Add", &[&x, biases], output, &[])
+    }
+
+    /// Batch normalization with the running statistics of `norm`.
+    fn batch_norm(&mut self, input: &str, norm: &str) -> String {
+        let gamma = format!("{}.gamma", norm);
+        let beta = format!("{}.beta", norm);
+        let mean = format!("{}.running_mean", norm);
+        let variance = format!("{}.running_variance", norm);
+        self.node(
+            "BatchNormalization",
+            &[input, &gamma, &beta, &mean, &variance],
+            norm,
+            &[float_attribute("epsilon", BATCH_NORM_EPSILON)],
+        )
+    }
+
+    /// Fully connected layer, like `fully_connected_pass`.
+    fn dense(&mut self, input: &str, weights: &str, biases: &str, output: &str) -> String {
+        self.node("Gemm", &[input, weights, biases], output, &[int_attribute("transB", 1)])
+    }
+
+    fn relu(&mut self, input: &str, output: &str) -> String {
+        self.node("Relu", &[input], output, &[])
+    }
+
+    fn flatten(&mut self, input: &str, output: &str) -> String {
+        self.node("Flatten", &[input], output, &[int_attribute("axis", 1)])
+    }
+}
+
+/// Encode the network as an ONNX model for inference, with the weights as
+/// 32-bit floats. The inputs are `input` as from `game_to_input` in NCHW
+/// order and `legal_mask` with 1 for legal moves and 0 for the rest. The
+/// outputs are `policy`, a softmax over the legal moves, and `value`.
+/// Batch normalization uses the running statistics.
+pub fn export(architecture: &Architecture, tensors: &[NamedTensor]) -> Vec<u8> {
+    let mut graph = Graph::default();
+    for tensor in tensors {
+        graph.initializer(&tensor.name, &onnx_dims(&tensor.shape), &tensor.data);
+    }
+    // Input convolution.
+    let x = graph.convolution("input", "input.kernels", "input.biases", 3, "input.conv");
+    let x = graph.batch_norm(&x, "input.norm");
+    let mut x = graph.relu(&x, "input.relu");
+    // Residual tower.
+    for block in 0..architecture.blocks {
+        let name = |layer: &str| format!("tower.{}.{}", block, layer);
+        let h = graph.convolution(&x, &name("kernels1"), &name("biases1"), 3, &name("conv1"));
+        let h = graph.batch_norm(&h, &name("norm1"));
+        let h = graph.relu(&h, &name("relu1"));
+        let h = graph.convolution(&h, &name("kernels2"), &name("biases2"), 3, &name("conv2"));
+        let h = graph.batch_norm(&h, &name("norm2"));
+        let h = graph.node("Add", &[&h, &x], &name("skip"), &[]);
+        x = graph.relu(&h, &name("relu2"));
+    }
+    // Policy head.
+    let p = graph.convolution(&x, "policy.kernels", "policy.kernel_biases", 1, "policy.conv");
+    let p = graph.relu(&p, "policy.relu");
+    let p = graph.flatten(&p, "policy.flatten");
+    let p = graph.dense(&p, "policy.weights", "policy.biases", "policy.logits");
+    graph.initializer("one", &[], &[1.]);
+    graph.initializer("illegal_logit", &[], &[ILLEGAL_LOGIT]);
+    let illegal = graph.node("Sub", &["one", "legal_mask"], "policy.illegal", &[]);
+    let offset = graph.node("Mul", &[&illegal, "illegal_logit"], "policy.offset", &[]);
+    let p = graph.node("Add", &[&p, &offset], "policy.masked_logits", &[]);
+    graph.node("Softmax", &[&p], "policy", &[int_attribute("axis", 1)]);
+    // Value head.
+    let v = graph.convolution(&x, "value.kernels", "value.kernel_biases", 1, "value.conv");
+    let v = graph.relu(&v, "value.relu");
+    let v = graph.flatten(&v, "value.flatten");
+    let v = graph.dense(&v, "value.hidden_weights", "value.hidden_biases", "value.hidden");
+    let v = graph.relu(&v, "value.hidden_relu");
+    let v = graph.dense(&v, "value.weights", "value.biases", "value.logit");
+    graph.node("Tanh", &[&v], "value", &[]);
+
+    let mut graph_proto = Message::default();
+    for node in graph.nodes.iter() {
+        graph_proto.message(1, node);
+    }
+    graph_proto.string(2, "onitama");
+    for initializer in graph.initializers.iter() {
+        graph_proto.message(5, initializer);
+    }
+    graph_proto
+        .message(11, &value_info("input", &[8, 5, 5]))
+        .message(11, &value_info("legal_mask", &[architecture.policy_size]))
+        .message(12, &value_info("policy", &[architecture.policy_size]))
+        .message(12, &value_info("value", &[1]));
+
+    let mut model = Message::default();
+    model
+        .int(1, IR_VERSION)
+        .string(2, "onitama-alpha-zero")
+        .message(7, &graph_proto)
+        .message(8, Message::default().int(2, OPSET_VERSION));
+    model.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use tensor::with_larger_stack;
+
+    use super::*;
+    use crate::{moves::POLICY_SIZE, network::Network};
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Value<'a> {
+        Varint(u64),
+        Bytes(&'a [u8]),
+        Fixed32(u32),
+    }
+
+    impl<'a> Value<'a> {
+        fn int(self) -> u64 {
+            match self {
+                Value::Varint(value) => value,
+                _ => panic!("expected a varint, found {:?}", self),
+            }
+        }
+
+        fn bytes(self) -> &'a [u8] {
+            match self {
+                Value::Bytes(bytes) => bytes,
+                _ => panic!("expected bytes, found {:?}", self),
+            }
+        }
+
+        fn string(self) -> &'a str {
+            std::str::from_utf8(self.bytes()).unwrap()
+        }
+    }
+
+    fn read_varint(data: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = data[0];
+            *data = &data[1..];
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte < 0x80 {
+                break;
+            }
+        }
+        value
+    }
+
+    /// All fields of a message in order, as field numbers and values.
+    fn decode(mut data: &[u8]) -> Vec<(u64, Value)> {
+        let mut fields = Vec::new();
+        while !data.is_empty() {
+            let key = read_varint(&mut data);
+            let value = match key & 7 {
+                0 => Value::Varint(read_varint(&mut data)),
+                2 => {
+                    let len = read_varint(&mut data) as usize;
+                    let (bytes, rest) = data.split_at(len);
+                    data = rest;
+                    Value::Bytes(bytes)
+                }
+                5 => {
+                    let (bytes, rest) = data.split_at(4);
+                    data = rest;
+                    Value::Fixed32(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                }
+                wire_type => panic!("unexpected wire type {}", wire_type),
+            };
+            fields.push((key >> 3, value));
+        }
+        fields
+    }
+
+    fn field<'a>(fields: &[(u64, Value<'a>)], number: u64) -> Vec<Value<'a>> {
+        fields
+            .iter()
+            .filter(|(n, _)| *n == number)
+            .map(|&(_, value)| value)
+            .collect()
+    }
+
+    #[test]
+    fn varint() {
+        let mut message = Message::default();
+        message.int(1, 300).int(2, -1);
+        assert_eq!(message.0[..3], [0x08, 0xAC, 0x02]);
+        let fields = decode(&message.0);
+        assert_eq!(fields[0], (1, Value::Varint(300)));
+        assert_eq!(fields[1].1.int() as i64, -1);
+    }
+
+    #[test]
+    fn graph_structure() {
+        with_larger_stack(|| {
+            let network = Network::<4>::init(2);
+            let tensors = network.named_tensors();
+            let model = export(&network.architecture(), &tensors);
+
+            let model = decode(&model);
+            assert_eq!(field(&model, 1)[0].int(), IR_VERSION as u64);
+            let opset = decode(field(&model, 8)[0].bytes());
+            assert_eq!(field(&opset, 2)[0].int(), OPSET_VERSION as u64);
+            let graph = decode(field(&model, 7)[0].bytes());
+
+            // Every weight is embedded with its ONNX shape.
+            let mut known = HashSet::new();
+            for initializer in field(&graph, 5) {
+                let initializer = decode(initializer.bytes());
+                let name = field(&initializer, 8)[0].string();
+                let dims: Vec<_> = field(&initializer, 1).iter().map(|dim| dim.int() as usize).collect();
+                let raw = field(&initializer, 9)[0].bytes();
+                assert_eq!(raw.len(), 4 * dims.iter().product::<usize>());
+                if let Some(tensor) = tensors.iter().find(|tensor| tensor.name == name) {
+                    assert_eq!(dims, onnx_dims(&tensor.shape));
+                    let first = f32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]);
+                    assert_eq!(first, tensor.data[0] as f32);
+                }
+                known.insert(name.to_string());
+            }
+            assert!(tensors.iter().all(|tensor| known.contains(&tensor.name)));
+            assert_eq!(
+                onnx_dims(&tensors.iter().find(|t| t.name == "input.kernels").unwrap().shape),
+                [4, 8, 3, 3]
+            );
+
+            let names = |number| -> Vec<String> {
+                field(&graph, number)
+                    .into_iter()
+                    .map(|value_info| field(&decode(value_info.bytes()), 1)[0].string().to_string())
+                    .collect()
+            };
+            assert_eq!(names(11), ["input", "legal_mask"]);
+            assert_eq!(names(12), ["policy", "value"]);
+            known.extend(names(11));
+
+            // The nodes are in topological order and only use known values.
+            let mut op_types = Vec::new();
+            for node in field(&graph, 1) {
+                let node = decode(node.bytes());
+                for input in field(&node, 1) {
+                    assert!(known.contains(input.string()), "unknown input {}", input.string());
+                }
+                for output in field(&node, 2) {
+                    assert!(known.insert(output.string().to_string()));
+                }
+                op_types.push(field(&node, 4)[0].string().to_string());
+            }
+            assert!(names(12).iter().all(|output| known.contains(output)));
+
+            let mut expected = vec!["Conv", "Add", "BatchNormalization", "Relu"];
+            for _ in 0..network.blocks() {
+                expected.extend(&["Conv", "Add", "BatchNormalization", "Relu"]);
+                expected.extend(&["Conv", "Add", "BatchNormalization", "Add", "Relu"]);
+            }
+            expected.extend(&["Conv", "Add", "Relu", "Flatten", "Gemm"]);
+            expected.extend(&["Sub", "Mul", "Add", "Softmax"]);
+            expected.extend(&["Conv", "Add", "Relu", "Flatten", "Gemm", "Relu", "Gemm", "Tanh"]);
+            assert_eq!(op_types, expected);
+
+            // The shape of the policy follows the action space.
+            let output = decode(field(&graph, 12)[0].bytes());
+            let type_proto = decode(field(&output, 2)[0].bytes());
+            let tensor_type = decode(field(&type_proto, 1)[0].bytes());
+            let shape = decode(field(&tensor_type, 2)[0].bytes());
+            let dims = field(&shape, 1);
+            assert_eq!(field(&decode(dims[0].bytes()), 2)[0].string(), "batch");
+            assert_eq!(field(&decode(dims[1].bytes()), 1)[0].int(), POLICY_SIZE as u64);
+        })
+    }
+}
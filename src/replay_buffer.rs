@@ -0,0 +1,253 @@
+use std::{collections::VecDeque, error::Error, fmt, fs, io};
+
+use onitama_move_gen::gen::Game;
+use rand::{
+    distributions::{Distribution, Uniform},
+    thread_rng,
+};
+use tensor::*;
+
+use crate::{alpha_zero::TrainingExample, moves::POLICY_SIZE};
+
+/// How much self-play data the replay buffer holds on to.
+#[derive(Clone, Copy, Debug)]
+pub enum Window {
+    /// Keep at most this many examples, dropping the oldest first.
+    Examples(usize),
+    /// Keep every example from this many of the most recent generations.
+    Generations(usize),
+}
+
+/// Sliding window over the training examples of the last few self-play
+/// generations.
+pub struct ReplayBuffer {
+    window: Window,
+    generations: VecDeque<Vec<TrainingExample>>,
+}
+
+// Game fields, improved policy and result.
+type SaveExample = (u32, u32, u32, u32, Vec<f64>, f64);
+
+/// Why a replay buffer could not be loaded.
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Malformed(String),
+    /// The examples were saved for another action space.
+    PolicySize { saved: usize, expected: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(err) => write!(f, "couldn't read file: {}", err),
+            LoadError::Malformed(reason) => write!(f, "malformed replay buffer file: {}", reason),
+            LoadError::PolicySize { saved, expected } => write!(
+                f,
+                "examples were saved with a policy of size {}, but {} is expected",
+                saved, expected
+            ),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> LoadError {
+        LoadError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for LoadError {
+    fn from(err: bincode::Error) -> LoadError {
+        LoadError::Malformed(err.to_string())
+    }
+}
+
+impl ReplayBuffer {
+    pub fn new(window: Window) -> ReplayBuffer {
+        ReplayBuffer {
+            window,
+            generations: VecDeque::new(),
+        }
+    }
+
+    /// Number of examples currently in the buffer.
+    pub fn len(&self) -> usize {
+        self.generations.iter().map(Vec::len).sum()
+    }
+
+    /// Add the examples of a new generation and evict what falls out of the
+    /// window.
+    pub fn push_generation(&mut self, examples: Vec<TrainingExample>) {
+        self.generations.push_back(examples);
+        match self.window {
+            Window::Generations(max) => {
+                while self.generations.len() > max {
+                    self.generations.pop_front();
+                }
+            }
+            Window::Examples(max) => {
+                let mut excess = self.len().saturating_sub(max);
+                while excess > 0 {
+                    let oldest = self.generations.front_mut().unwrap();
+                    if oldest.len() <= excess {
+                        excess -= oldest.len();
+                        self.generations.pop_front();
+                    } else {
+                        oldest.drain(..excess);
+                        excess = 0;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Iterate over all examples, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &TrainingExample> {
+        self.generations.iter().flatten()
+    }
+
+    /// Sample examples uniformly (with replacement) from the whole buffer.
+    pub fn sample(&self, amount: usize) -> Vec<&TrainingExample> {
+        let len = self.len();
+        if len == 0 {
+            return Vec::new();
+        }
+        let mut rng = thread_rng();
+        let distr = Uniform::new(0, len);
+        (0..amount).map(|_| self.get(distr.sample(&mut rng))).collect()
+    }
+
+    fn get(&self, mut index: usize) -> &TrainingExample {
+        for generation in self.generations.iter() {
+            if index < generation.len() {
+                return &generation[index];
+            }
+            index -= generation.len();
+        }
+        unreachable!()
+    }
+
+    fn get_save_data(&self) -> Vec<Vec<SaveExample>> {
+        self.generations
+            .iter()
+            .map(|generation| {
+                generation
+                    .iter()
+                    .map(|example| {
+                        let Game { my, other, cards, table } = example.game;
+                        (
+                            my,
+                            other,
+                            cards,
+                            table,
+                            example.improved_policy.iter().cloned().collect(),
+                            example.result,
+                        )
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn from_save_data(data: Vec<Vec<SaveExample>>, window: Window) -> Result<ReplayBuffer, LoadError> {
+        let mut replay_buffer = ReplayBuffer::new(window);
+        for generation in data.into_iter() {
+            let examples = generation
+                .into_iter()
+                .map(|(my, other, cards, table, policy, result)| {
+                    if policy.len() != POLICY_SIZE {
+                        return Err(LoadError::PolicySize {
+                            saved: policy.len(),
+                            expected: POLICY_SIZE,
+                        });
+                    }
+                    let mut iter = policy.into_iter();
+                    Ok(TrainingExample {
+                        game: Game { my, other, cards, table },
+                        improved_policy: Vector::new([(); POLICY_SIZE].map(|()| iter.next().unwrap())),
+                        result,
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+            replay_buffer.push_generation(examples);
+        }
+        Ok(replay_buffer)
+    }
+
+    pub fn save(&self, path: &str) {
+        let data = bincode::serialize(&self.get_save_data()).unwrap();
+        fs::write(path, data).expect("couldn't save replay buffer to file");
+    }
+
+    pub fn load(path: &str, window: Window) -> Result<ReplayBuffer, LoadError> {
+        let data = fs::read(path)?;
+        ReplayBuffer::from_save_data(bincode::deserialize(&data)?, window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rand_game::random_game;
+
+    fn generation(size: usize, result: f64) -> Vec<TrainingExample> {
+        (0..size)
+            .map(|_| TrainingExample {
+                game: random_game(),
+                improved_policy: Vector::rand(rand_distr::Uniform::new(0., 1.)),
+                result,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn generation_window() {
+        let mut replay_buffer = ReplayBuffer::new(Window::Generations(2));
+        replay_buffer.push_generation(generation(3, 1.));
+        replay_buffer.push_generation(generation(4, 2.));
+        replay_buffer.push_generation(generation(5, 3.));
+        assert_eq!(replay_buffer.len(), 9);
+        assert!(replay_buffer.iter().all(|example| example.result > 1.));
+    }
+
+    #[test]
+    fn example_window() {
+        let mut replay_buffer = ReplayBuffer::new(Window::Examples(6));
+        replay_buffer.push_generation(generation(3, 1.));
+        replay_buffer.push_generation(generation(4, 2.));
+        assert_eq!(replay_buffer.len(), 6);
+        assert_eq!(replay_buffer.iter().filter(|example| example.result == 1.).count(), 2);
+        replay_buffer.push_generation(generation(7, 3.));
+        assert_eq!(replay_buffer.len(), 6);
+        assert!(replay_buffer.iter().all(|example| example.result == 3.));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut orig = ReplayBuffer::new(Window::Generations(3));
+        orig.push_generation(generation(3, 1.));
+        orig.push_generation(generation(2, -1.));
+        orig.save("test_replay.data");
+        let replay_buffer = ReplayBuffer::load("test_replay.data", Window::Generations(3)).unwrap();
+        fs::remove_file("test_replay.data").unwrap();
+        assert_eq!(orig.generations.len(), replay_buffer.generations.len());
+        for (a, b) in orig.iter().zip(replay_buffer.iter()) {
+            assert_eq!(a.game.my, b.game.my);
+            assert_eq!(a.game.other, b.game.other);
+            assert_eq!(a.game.cards, b.game.cards);
+            assert_eq!(a.game.table, b.game.table);
+            assert_eq!(a.improved_policy, b.improved_policy);
+            assert_eq!(a.result, b.result);
+        }
+    }
+
+    #[test]
+    fn reject_other_action_spaces() {
+        let data = vec![vec![(0, 0, 0, 0, vec![0.; POLICY_SIZE + 1], 1.)]];
+        let result = ReplayBuffer::from_save_data(data, Window::Generations(1));
+        assert!(matches!(result, Err(LoadError::PolicySize { .. })));
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    mem,
+};
+
+use onitama_move_gen::gen::Game;
+
+use crate::convert::{game_key, GameKey};
+
+/// What is known about a position, shared by every node for it.
+struct TableEntry {
+    /// The whole position, so that positions with the same hash are told apart.
+    key: GameKey,
+    /// Priors of the moves in the order `Game::forward` generates them.
+    priors: Vec<f64>,
+    /// Eval from the network for the player to move.
+    value: f64,
+    /// Evals backed up through any node for this position.
+    visits: u32,
+    total_reward: f64,
+    /// How often the entry was useful, used to decide what to keep.
+    uses: u32,
+}
+
+/// Bounded map from positions to their evaluations, so that positions which
+/// are reached through different move orders are only evaluated once.
+///
+/// Every bucket has two slots. The first keeps the entry which was used the
+/// most, the second is always replaced by new positions.
+pub struct TranspositionTable {
+    buckets: Vec<[Option<TableEntry>; 2]>,
+    /// Also share the backed up evals, not just the network evaluations.
+    share_statistics: bool,
+}
+
+fn hash(key: &GameKey) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl TranspositionTable {
+    pub fn new(buckets: usize, share_statistics: bool) -> TranspositionTable {
+        assert!(buckets > 0);
+        TranspositionTable {
+            buckets: (0..buckets).map(|_| [None, None]).collect(),
+            share_statistics,
+        }
+    }
+
+    /// Number of positions currently stored.
+    pub fn len(&self) -> usize {
+        self.buckets.iter().flatten().filter(|slot| slot.is_some()).count()
+    }
+
+    pub fn clear(&mut self) {
+        for bucket in self.buckets.iter_mut() {
+            *bucket = [None, None];
+        }
+    }
+
+    /// The hash only picks the bucket, entries are matched by the whole key.
+    fn bucket_mut(&mut self, key: &GameKey) -> &mut [Option<TableEntry>; 2] {
+        let index = (hash(key) % self.buckets.len() as u64) as usize;
+        &mut self.buckets[index]
+    }
+
+    fn entry_mut(&mut self, key: &GameKey) -> Option<&mut TableEntry> {
+        self.bucket_mut(key).iter_mut().flatten().find(|entry| entry.key == *key)
+    }
+
+    /// Get the priors of the moves and the eval of a stored position. With
+    /// shared statistics the eval is the mean of everything backed up so far.
+    pub fn get(&mut self, game: &Game) -> Option<(&[f64], f64)> {
+        let key = game_key(game);
+        let share_statistics = self.share_statistics;
+        self.entry_mut(&key)?.uses += 1;
+        promote(self.bucket_mut(&key));
+        let entry = self.entry_mut(&key).unwrap();
+        let eval = if share_statistics && entry.visits > 0 {
+            entry.total_reward / entry.visits as f64
+        } else {
+            entry.value
+        };
+        Some((entry.priors.as_slice(), eval))
+    }
+
+    /// Store the evaluation of a position.
+    pub fn insert(&mut self, game: &Game, priors: Vec<f64>, value: f64) {
+        let key = game_key(game);
+        if let Some(entry) = self.entry_mut(&key) {
+            entry.priors = priors;
+            entry.value = value;
+            return;
+        }
+        self.bucket_mut(&key)[1] = Some(TableEntry {
+            key,
+            priors,
+            value,
+            visits: 0,
+            total_reward: 0.,
+            uses: 0,
+        });
+        promote(self.bucket_mut(&key));
+    }
+
+    /// Add an eval backed up through a node for this position.
+    /// Does nothing unless statistics are shared.
+    pub fn record(&mut self, game: &Game, eval: f64) {
+        if !self.share_statistics {
+            return;
+        }
+        let key = game_key(game);
+        if let Some(entry) = self.entry_mut(&key) {
+            entry.visits += 1;
+            entry.total_reward += eval;
+            entry.uses += 1;
+            promote(self.bucket_mut(&key));
+        }
+    }
+}
+
+/// Move the entry in the replaceable slot into the kept slot if it has been
+/// used more than the one there.
+fn promote(bucket: &mut [Option<TableEntry>; 2]) {
+    let more_used = match bucket {
+        [None, Some(_)] => true,
+        [Some(kept), Some(replaceable)] => replaceable.uses > kept.uses,
+        _ => false,
+    };
+    if more_used {
+        let [kept, replaceable] = bucket;
+        mem::swap(kept, replaceable);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rand_game::random_game;
+
+    #[test]
+    fn insert_and_get() {
+        let mut table = TranspositionTable::new(16, false);
+        let game = random_game();
+        assert!(table.get(&game).is_none());
+        table.insert(&game, vec![0.25, 0.75], 0.5);
+        let (priors, eval) = table.get(&game).unwrap();
+        assert_eq!(priors, &[0.25, 0.75]);
+        assert_eq!(eval, 0.5);
+        // Without shared statistics the network eval is kept.
+        table.record(&game, -1.);
+        assert_eq!(table.get(&game).unwrap().1, 0.5);
+    }
+
+    #[test]
+    fn shared_statistics() {
+        let mut table = TranspositionTable::new(16, true);
+        let game = random_game();
+        table.insert(&game, vec![1.], 0.5);
+        table.record(&game, 1.);
+        table.record(&game, 0.);
+        assert_eq!(table.get(&game).unwrap().1, 0.5);
+        table.record(&game, 1.);
+        assert!((table.get(&game).unwrap().1 - 2. / 3.).abs() < 1e-12);
+    }
+
+    #[test]
+    fn positions_in_one_bucket_are_kept_apart() {
+        let mut table = TranspositionTable::new(1, false);
+        let (a, b) = loop {
+            let (a, b) = (random_game(), random_game());
+            if game_key(&a) != game_key(&b) {
+                break (a, b);
+            }
+        };
+        table.insert(&a, vec![1.], 0.);
+        assert!(table.get(&b).is_none());
+        table.insert(&b, vec![0.5, 0.5], 1.);
+        assert_eq!(table.get(&a).unwrap(), (&[1.][..], 0.));
+        assert_eq!(table.get(&b).unwrap(), (&[0.5, 0.5][..], 1.));
+    }
+
+    #[test]
+    fn bounded_and_keeps_most_used() {
+        // A single bucket holds at most two positions.
+        let mut table = TranspositionTable::new(1, false);
+        let games: Vec<_> = (0..10).map(|_| random_game()).collect();
+        table.insert(&games[0], vec![], 0.);
+        for _ in 0..3 {
+            table.get(&games[0]);
+        }
+        for game in games.iter().skip(1) {
+            table.insert(game, vec![], 0.);
+            assert!(table.len() <= 2);
+        }
+        assert!(table.get(&games[0]).is_some());
+        table.clear();
+        assert_eq!(table.len(), 0);
+    }
+}
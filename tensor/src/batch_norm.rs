@@ -0,0 +1,287 @@
+use super::*;
+
+/// Added to the variance so that constant channels do not divide by zero.
+pub const BATCH_NORM_EPSILON: f64 = 1e-5;
+/// How far the statistics of each training batch move the running statistics.
+const MOMENTUM: f64 = 0.1;
+
+/// Which statistics batch normalization normalizes with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatchNormMode {
+    /// The statistics of the batch itself.
+    Train,
+    /// The running statistics gathered during training.
+    Inference,
+}
+
+/// Mean and variance of every channel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BatchStatistics<const C: usize> {
+    pub mean: Tensor1<f64, C>,
+    pub variance: Tensor1<f64, C>,
+}
+
+/// Normalizes every channel (the last dimension) of a batch of `Tensor3`s
+/// and then scales it by `gamma` and shifts it by `beta`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BatchNorm<const C: usize> {
+    pub gamma: Tensor1<f64, C>,
+    pub beta: Tensor1<f64, C>,
+    pub running: BatchStatistics<C>,
+}
+
+/// Values from the forward pass which the backward pass needs.
+pub struct BatchNormCache<const D1: usize, const D2: usize, const C: usize>
+where
+    [(); D1 * D2 * C]: ,
+{
+    mode: BatchNormMode,
+    statistics: BatchStatistics<C>,
+    normalized: Vec<Tensor3<f64, D1, D2, C>>,
+}
+
+impl<const D1: usize, const D2: usize, const C: usize> BatchNormCache<D1, D2, C>
+where
+    [(); D1 * D2 * C]: ,
+{
+    /// Statistics the batch was normalized with.
+    pub fn statistics(&self) -> &BatchStatistics<C> {
+        &self.statistics
+    }
+}
+
+/// Starts out as the identity on inputs with zero mean and unit variance.
+impl<const C: usize> Default for BatchNorm<C> {
+    fn default() -> Self {
+        BatchNorm {
+            gamma: Tensor1::new([1.; C]),
+            beta: Tensor1::default(),
+            running: BatchStatistics {
+                mean: Tensor1::default(),
+                variance: Tensor1::new([1.; C]),
+            },
+        }
+    }
+}
+
+impl<const C: usize> BatchNorm<C> {
+    /// Normalize a batch with the statistics picked by `mode`.
+    /// The running statistics are only changed by `update_running`.
+    pub fn forward_batch<const D1: usize, const D2: usize>(
+        &self,
+        inputs: &[Tensor3<f64, D1, D2, C>],
+        mode: BatchNormMode,
+    ) -> (Vec<Tensor3<f64, D1, D2, C>>, BatchNormCache<D1, D2, C>)
+    where
+        [(); D1 * D2 * C]: ,
+    {
+        let statistics = match mode {
+            BatchNormMode::Train => batch_statistics(inputs),
+            BatchNormMode::Inference => self.running,
+        };
+        let inv_std = statistics.variance.map(|v| 1. / (v + BATCH_NORM_EPSILON).sqrt());
+        let normalized: Vec<_> = inputs
+            .iter()
+            .map(|input| {
+                let mut x = *input;
+                for (i, elem) in x.0.iter_mut().enumerate() {
+                    let c = i / (D1 * D2);
+                    *elem = (*elem - statistics.mean.0[c]) * inv_std.0[c];
+                }
+                x
+            })
+            .collect();
+        let outputs = normalized
+            .iter()
+            .map(|x| {
+                let mut y = *x;
+                for (i, elem) in y.0.iter_mut().enumerate() {
+                    let c = i / (D1 * D2);
+                    *elem = *elem * self.gamma.0[c] + self.beta.0[c];
+                }
+                y
+            })
+            .collect();
+        let cache = BatchNormCache {
+            mode,
+            statistics,
+            normalized,
+        };
+        (outputs, cache)
+    }
+
+    /// Move the running statistics towards the statistics of a training batch.
+    pub fn update_running(&mut self, batch: &BatchStatistics<C>) {
+        self.running.mean = self.running.mean.scale(1. - MOMENTUM) + &batch.mean.scale(MOMENTUM);
+        self.running.variance = self.running.variance.scale(1. - MOMENTUM) + &batch.variance.scale(MOMENTUM);
+    }
+
+    /// Back-propagate through the layer.
+    /// Returns the derivatives for gamma, beta and the inputs.
+    pub fn backward<const D1: usize, const D2: usize>(
+        &self,
+        cache: &BatchNormCache<D1, D2, C>,
+        output_derivatives: &[Tensor3<f64, D1, D2, C>],
+    ) -> (Tensor1<f64, C>, Tensor1<f64, C>, Vec<Tensor3<f64, D1, D2, C>>)
+    where
+        [(); D1 * D2 * C]: ,
+    {
+        let mut gamma_derivatives = [0.; C];
+        let mut beta_derivatives = [0.; C];
+        for (x, dy) in cache.normalized.iter().zip(output_derivatives.iter()) {
+            for (i, (x, dy)) in x.0.iter().zip(dy.0.iter()).enumerate() {
+                let c = i / (D1 * D2);
+                gamma_derivatives[c] += dy * x;
+                beta_derivatives[c] += dy;
+            }
+        }
+        let inv_std = cache.statistics.variance.map(|v| 1. / (v + BATCH_NORM_EPSILON).sqrt());
+        let n = (output_derivatives.len() * D1 * D2) as f64;
+        let input_derivatives = cache
+            .normalized
+            .iter()
+            .zip(output_derivatives.iter())
+            .map(|(x, dy)| {
+                let mut dx = *dy;
+                for (i, (elem, x)) in dx.0.iter_mut().zip(x.0.iter()).enumerate() {
+                    let c = i / (D1 * D2);
+                    let scale = self.gamma.0[c] * inv_std.0[c];
+                    *elem = match cache.mode {
+                        // The statistics depend on every input of the batch.
+                        BatchNormMode::Train => {
+                            scale * (*elem - (beta_derivatives[c] + x * gamma_derivatives[c]) / n)
+                        }
+                        BatchNormMode::Inference => scale * *elem,
+                    };
+                }
+                dx
+            })
+            .collect();
+        (Tensor1::new(gamma_derivatives), Tensor1::new(beta_derivatives), input_derivatives)
+    }
+}
+
+/// Mean and (biased) variance of every channel over a whole batch.
+fn batch_statistics<const D1: usize, const D2: usize, const C: usize>(
+    inputs: &[Tensor3<f64, D1, D2, C>],
+) -> BatchStatistics<C>
+where
+    [(); D1 * D2 * C]: ,
+{
+    let n = (inputs.len() * D1 * D2) as f64;
+    let mut mean = [0.; C];
+    for input in inputs {
+        for (i, x) in input.0.iter().enumerate() {
+            mean[i / (D1 * D2)] += x / n;
+        }
+    }
+    let mut variance = [0.; C];
+    for input in inputs {
+        for (i, x) in input.0.iter().enumerate() {
+            let c = i / (D1 * D2);
+            variance[c] += (x - mean[c]).powi(2) / n;
+        }
+    }
+    BatchStatistics {
+        mean: Tensor1::new(mean),
+        variance: Tensor1::new(variance),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_norm() -> BatchNorm<3> {
+        let distr = rand_distr::Uniform::<f64>::new(0.5, 1.5);
+        BatchNorm {
+            gamma: Tensor1::rand(distr),
+            beta: Tensor1::rand(distr),
+            running: BatchStatistics {
+                mean: Tensor1::rand(distr),
+                variance: Tensor1::rand(distr),
+            },
+        }
+    }
+
+    #[test]
+    fn train_normalizes_every_channel() {
+        let distr = rand_distr::Uniform::<f64>::new(-3., 5.);
+        let inputs: Vec<_> = (0..4).map(|_| Tensor3::<_, 2, 3, 3>::rand(distr)).collect();
+        let (outputs, cache) = BatchNorm::default().forward_batch(&inputs, BatchNormMode::Train);
+        let statistics = batch_statistics(&outputs);
+        for c in 0..3 {
+            assert!(statistics.mean.0[c].abs() < 1e-9);
+            assert!((statistics.variance.0[c] - 1.).abs() < 1e-3);
+        }
+        assert_eq!(cache.statistics(), &batch_statistics(&inputs));
+    }
+
+    #[test]
+    fn inference_uses_running_statistics() {
+        let norm = random_norm();
+        let input = Tensor3::<_, 2, 3, 3>::rand(rand_distr::Uniform::new(-1., 1.));
+        let (outputs, _) = norm.forward_batch(&[input], BatchNormMode::Inference);
+        for (i, (&x, &y)) in input.0.iter().zip(outputs[0].0.iter()).enumerate() {
+            let c = i / 6;
+            let std_dev = (norm.running.variance.0[c] + BATCH_NORM_EPSILON).sqrt();
+            let expected = (x - norm.running.mean.0[c]) / std_dev * norm.gamma.0[c] + norm.beta.0[c];
+            assert!((y - expected).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn update_running() {
+        let mut norm = BatchNorm::<2>::default();
+        let batch = BatchStatistics {
+            mean: Tensor1::new([1., -1.]),
+            variance: Tensor1::new([2., 0.]),
+        };
+        norm.update_running(&batch);
+        assert_eq!(norm.running.mean, Tensor1::new([MOMENTUM, -MOMENTUM]));
+        assert_eq!(norm.running.variance, Tensor1::new([1. + MOMENTUM, 1. - MOMENTUM]));
+    }
+
+    #[test]
+    fn backward_matches_finite_differences() {
+        let distr = rand_distr::Uniform::<f64>::new(-1., 1.);
+        for &mode in [BatchNormMode::Train, BatchNormMode::Inference].iter() {
+            let norm = random_norm();
+            let inputs: Vec<_> = (0..3).map(|_| Tensor3::<_, 2, 2, 3>::rand(distr)).collect();
+            // The loss is a fixed weighted sum of the outputs.
+            let weights: Vec<_> = (0..3).map(|_| Tensor3::<_, 2, 2, 3>::rand(distr)).collect();
+            let loss = |norm: &BatchNorm<3>, inputs: &[Tensor3<f64, 2, 2, 3>]| {
+                let (outputs, _) = norm.forward_batch(inputs, mode);
+                outputs.iter().zip(weights.iter()).map(|(y, w)| (*y * w).sum()).sum::<f64>()
+            };
+            let (_, cache) = norm.forward_batch(&inputs, mode);
+            let (gamma, beta, input_derivatives) = norm.backward(&cache, &weights);
+            let epsilon = 1e-6;
+            let estimate = |f: &dyn Fn(f64) -> f64| (f(epsilon) - f(-epsilon)) / (2. * epsilon);
+            for c in 0..3 {
+                let d_gamma = estimate(&|e| {
+                    let mut shifted = norm;
+                    shifted.gamma.0[c] += e;
+                    loss(&shifted, &inputs)
+                });
+                assert!((d_gamma - gamma.0[c]).abs() < 1e-6);
+                let d_beta = estimate(&|e| {
+                    let mut shifted = norm;
+                    shifted.beta.0[c] += e;
+                    loss(&shifted, &inputs)
+                });
+                assert!((d_beta - beta.0[c]).abs() < 1e-6);
+            }
+            for b in 0..inputs.len() {
+                for i in 0..2 * 2 * 3 {
+                    let d_input = estimate(&|e| {
+                        let mut shifted = inputs.clone();
+                        shifted[b].0[i] += e;
+                        loss(&norm, &shifted)
+                    });
+                    assert!((d_input - input_derivatives[b].0[i]).abs() < 1e-5);
+                }
+            }
+        }
+    }
+}
@@ -16,7 +16,7 @@ macro_rules! conv_inter {
 }
 
 pub struct ConvolutionIntermediate<const X: usize, T: Tensor<Complex64, X>> {
-    tensor: T,
+    pub(crate) tensor: T,
 }
 
 #[allow(non_snake_case)]
@@ -167,6 +167,26 @@ where
     }
 }
 
+impl<const C: usize, const R: usize> Tensor2<f64, C, R>
+where
+    [(); C * R]: ,
+{
+    /// Transform into the frequency domain, padded with zeros to `X1` by `X2`.
+    /// Products of transformed tensors are convolutions, so a transformed
+    /// kernel can be reused for many inputs.
+    pub fn fft<const X1: usize, const X2: usize>(
+        self,
+        fft_planner: &mut FftPlanner<f64>,
+    ) -> conv_inter!(X1 * X2, Tensor2, X1 X2)
+    where
+        [(); X1 * X2]: ,
+    {
+        let mut data = to_complex_2::<C, R, X1, X2>(self);
+        apply_fft_2::<X1, X2>(&mut data, fft_planner, FftDirection::Forward);
+        ConvolutionIntermediate { tensor: Tensor2(data) }
+    }
+}
+
 impl<const D1: usize, const D2: usize, const D3: usize> Tensor3<f64, D1, D2, D3>
 where
     [(); D1 * D2 * D3]: ,
@@ -282,6 +302,22 @@ mod tests {
         assert!((naive - &fft).map(f64::abs).sum() < 1e-9);
     }
 
+    #[test]
+    fn fft_product() {
+        let distr = rand_distr::Uniform::new(-10., 10.);
+        let a = Tensor2::<_, 5, 5>::rand(distr);
+        let kernel = Tensor2::<_, 3, 3>::rand(distr);
+        let mut fft_planner = FftPlanner::new();
+        let direct = a.convolve_fft(kernel, &mut fft_planner).finish(&mut fft_planner);
+        let a_fft = a.fft::<7, 7>(&mut fft_planner);
+        let kernel_fft = kernel.rev().fft::<7, 7>(&mut fft_planner);
+        let product = ConvolutionIntermediate {
+            tensor: a_fft.tensor * &kernel_fft.tensor,
+        }
+        .finish(&mut fft_planner);
+        assert!((direct - &product).map(f64::abs).sum() < 1e-9);
+    }
+
     #[test]
     fn multiple_pass() {
         let a = Tensor1::new([1., 2., 3., 4., 5., 6.]);
@@ -0,0 +1,77 @@
+use std::f64::consts::PI;
+
+use rand::{distributions::Distribution, Rng};
+
+/// Weight initialization schemes, scaled by the number of inputs (`fan_in`)
+/// and outputs (`fan_out`) of the layer that a weight connects.
+/// For a convolution these are the kernel size times the input and output
+/// channels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Init {
+    /// He/Kaiming, for layers followed by relu. Variance `2 / fan_in`.
+    HeNormal,
+    HeUniform,
+    /// Xavier/Glorot, for layers followed by tanh, sigmoid or softmax.
+    /// Variance `2 / (fan_in + fan_out)`.
+    XavierNormal,
+    XavierUniform,
+}
+
+impl Init {
+    pub fn variance(self, fan_in: usize, fan_out: usize) -> f64 {
+        match self {
+            Init::HeNormal | Init::HeUniform => 2. / fan_in as f64,
+            Init::XavierNormal | Init::XavierUniform => 2. / (fan_in + fan_out) as f64,
+        }
+    }
+
+    /// Distribution of the weights of a layer, to be used with
+    /// `RandomTensor::rand`.
+    pub fn distribution(self, fan_in: usize, fan_out: usize) -> InitDistribution {
+        InitDistribution {
+            normal: matches!(self, Init::HeNormal | Init::XavierNormal),
+            std_dev: self.variance(fan_in, fan_out).sqrt(),
+        }
+    }
+}
+
+/// Normal or uniform distribution with zero mean.
+#[derive(Clone, Copy, Debug)]
+pub struct InitDistribution {
+    normal: bool,
+    std_dev: f64,
+}
+
+impl Distribution<f64> for InitDistribution {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> f64 {
+        if self.normal {
+            // Box-Muller transform, u1 is in (0, 1] so that its log is finite.
+            let u1 = 1. - rng.gen::<f64>();
+            let u2 = rng.gen::<f64>();
+            self.std_dev * (-2. * u1.ln()).sqrt() * (2. * PI * u2).cos()
+        } else {
+            // The uniform distribution on [-a, a) has variance a^2 / 3.
+            let a = self.std_dev * 3f64.sqrt();
+            rng.gen_range(-a..a)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn sample_variance() {
+        for &init in [Init::HeNormal, Init::HeUniform, Init::XavierNormal, Init::XavierUniform].iter() {
+            let weights = Tensor3::<f64, 100, 100, 2>::rand(init.distribution(50, 150));
+            let n = weights.get_data_ref().len() as f64;
+            let mean = weights.sum() / n;
+            let variance = weights.map(|w| (w - mean).powi(2)).sum() / n;
+            let expected = init.variance(50, 150);
+            assert!(mean.abs() < 0.01);
+            assert!((variance - expected).abs() < 0.05 * expected);
+        }
+    }
+}
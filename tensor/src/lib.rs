@@ -6,25 +6,39 @@ extern crate test;
 use std::fmt::{Debug, Display};
 
 mod array_init;
+mod batch_norm;
 mod convolution;
 mod convolution_fft;
 mod default;
 mod display;
 mod elementwise;
 mod ml;
+mod optimizer;
+mod schedule;
 mod shape;
 mod slice;
 mod tensor;
 
+#[cfg(feature = "rand")]
+mod init;
 #[cfg(feature = "rand")]
 mod random;
 
 pub use rustfft::FftPlanner;
 #[cfg(feature = "rand")]
-pub use {crate::random::RandomTensor, rand::distributions as rand_distr};
+pub use {
+    crate::{
+        init::{Init, InitDistribution},
+        random::RandomTensor,
+    },
+    rand::distributions as rand_distr,
+};
 
 pub use crate::{
+    batch_norm::{BatchNorm, BatchNormCache, BatchNormMode, BatchStatistics, BATCH_NORM_EPSILON},
     elementwise::ElementWiseTensor,
     ml::{d_relu, relu, sig, with_larger_stack},
+    optimizer::{Adam, Momentum, Optimizer, Sgd},
+    schedule::Schedule,
     tensor::{Matrix, Tensor, Tensor1, Tensor2, Tensor3, Vector},
 };
@@ -6,8 +6,10 @@ use std::{
     ops::{Add, Mul},
 };
 
+use rustfft::num_complex::Complex64;
+
 use super::*;
-use crate::convolution_fft::fft_l;
+use crate::convolution_fft::{fft_l, ConvolutionIntermediate};
 
 /// Rectilinear unit.
 pub fn relu(x: f64) -> f64 {
@@ -77,6 +79,72 @@ where
         }
         Tensor3::new(data) + biases
     }
+
+    /// Convolution pass over a whole batch of inputs.
+    /// Each kernel is transformed into the frequency domain only once for the
+    /// whole batch and channels are summed before transforming back.
+    pub fn convolution_pass_batch<const N: usize, const K_D1: usize, const K_D2: usize>(
+        inputs: &[Self],
+        kernels: &[Tensor3<f64, K_D1, K_D2, D3>; N],
+        biases: &Tensor3<f64, D1, D2, N>,
+        fft_planner: &mut FftPlanner<f64>,
+    ) -> Vec<Tensor3<f64, D1, D2, N>>
+    where
+        // input tensors
+        [(); D1 * D2 * D3]: ,
+        [(); K_D1 * K_D2 * D3]: ,
+        // intermediate channel tensors
+        [(); D1 * D2 * 1]: ,
+        [(); K_D1 * K_D2 * 1]: ,
+        [(); D1 * D2]: ,
+        [(); K_D1 * K_D2]: ,
+        // convolution intermediate tensor
+        [(); fft_l(D1, K_D1) * fft_l(D2, K_D2)]: ,
+        // output tensor
+        [(); D1 * D2 * N]: ,
+    {
+        // Transform every channel of every input once.
+        let transformed_inputs: Vec<Vec<_>> = inputs
+            .iter()
+            .map(|input| {
+                (0..D3)
+                    .map(|channel| {
+                        input
+                            .slice::<D1, D2, 1>(0, 0, channel)
+                            .squeeze()
+                            .fft::<{ fft_l(D1, K_D1) }, { fft_l(D2, K_D2) }>(fft_planner)
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut outputs = vec![*biases; inputs.len()];
+        for (n, kernel) in kernels.iter().enumerate() {
+            let transformed_kernel: Vec<_> = (0..D3)
+                .map(|channel| {
+                    kernel
+                        .slice::<K_D1, K_D2, 1>(0, 0, channel)
+                        .squeeze()
+                        .rev()
+                        .fft::<{ fft_l(D1, K_D1) }, { fft_l(D2, K_D2) }>(fft_planner)
+                })
+                .collect();
+            for (output, channels) in outputs.iter_mut().zip(transformed_inputs.iter()) {
+                // Convolution is linear, so sum in the frequency domain.
+                let mut sum = Tensor2::<Complex64, { fft_l(D1, K_D1) }, { fft_l(D2, K_D2) }>::default();
+                for (channel, kernel_channel) in channels.iter().zip(transformed_kernel.iter()) {
+                    sum += &(channel.tensor * &kernel_channel.tensor);
+                }
+                let res = ConvolutionIntermediate { tensor: sum }
+                    .finish(fft_planner)
+                    .slice::<D1, D2>((K_D1 - 1) / 2, (K_D2 - 1) / 2);
+                for (i, val) in res.into_iter().enumerate() {
+                    output.0[n * D1 * D2 + i] += val;
+                }
+            }
+        }
+        outputs
+    }
 }
 
 impl<T, const L: usize> Tensor1<T, L>
@@ -100,6 +168,26 @@ where
         Tensor1(data) + biases
     }
 
+    /// Fully connected pass over a whole batch of inputs.
+    /// Every row of weights is read only once for the whole batch.
+    pub fn fully_connected_pass_batch<const N: usize>(
+        inputs: &[Self],
+        weights: &[Tensor1<T, L>; N],
+        biases: &Tensor1<T, N>,
+    ) -> Vec<Tensor1<T, N>>
+    where
+        T: Add<Output = T> + Mul<Output = T> + Sum<T> + PartialOrd,
+        [(); L * N]: ,
+    {
+        let mut outputs = vec![*biases; inputs.len()];
+        for (n, row) in weights.iter().enumerate() {
+            for (output, &input) in outputs.iter_mut().zip(inputs.iter()) {
+                output.0[n] = (input * row).sum() + output.0[n];
+            }
+        }
+        outputs
+    }
+
     /// Split the tensor by separating the last elem.
     pub fn split_last(&self) -> (Tensor1<T, { L - 1 }>, T)
     where
@@ -120,6 +208,25 @@ impl<const L: usize> Tensor1<f64, L> {
         let exp = self.map(|x| f64::exp(x - b));
         exp.scale(1. / exp.sum())
     }
+
+    /// Softmax over only the elements where `mask` is set.
+    /// The other elements become zero.
+    pub fn masked_softmax(self, mask: &[bool; L]) -> Self {
+        let b = self
+            .0
+            .iter()
+            .zip(mask.iter())
+            .filter(|(_, &set)| set)
+            .map(|(&x, _)| x)
+            .fold(f64::NAN, f64::max);
+        let mut exp = self.map(|x| f64::exp(x - b));
+        for (elem, &set) in exp.0.iter_mut().zip(mask.iter()) {
+            if !set {
+                *elem = 0.;
+            }
+        }
+        exp.scale(1. / exp.sum())
+    }
 }
 
 use std::thread;
@@ -139,6 +246,52 @@ where
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convolution_pass_batch() {
+        with_larger_stack(|| {
+            let distr = rand_distr::Uniform::<f64>::new(-1., 1.);
+            let mut fft_planner = FftPlanner::new();
+            let inputs: Vec<_> = (0..3).map(|_| Tensor3::<_, 5, 5, 4>::rand(distr)).collect();
+            let kernels = [(); 6].map(|()| Tensor3::<_, 3, 3, 4>::rand(distr));
+            let biases = Tensor3::rand(distr);
+            let batch = Tensor3::convolution_pass_batch(&inputs, &kernels, &biases, &mut fft_planner);
+            for (input, output) in inputs.iter().zip(batch.iter()) {
+                let single = input.convolution_pass(&kernels, &biases, &mut fft_planner);
+                assert!((single - output).map(f64::abs).sum() < 1e-9);
+            }
+        })
+    }
+
+    #[test]
+    fn fully_connected_pass_batch() {
+        let distr = rand_distr::Uniform::<f64>::new(-1., 1.);
+        let inputs: Vec<_> = (0..3).map(|_| Tensor1::<_, 20>::rand(distr)).collect();
+        let weights = [(); 10].map(|()| Tensor1::rand(distr));
+        let biases = Tensor1::rand(distr);
+        let batch = Tensor1::fully_connected_pass_batch(&inputs, &weights, &biases);
+        for (input, output) in inputs.iter().zip(batch.iter()) {
+            assert_eq!(&input.fully_connected_pass(&weights, &biases), output);
+        }
+    }
+
+    #[test]
+    fn masked_softmax() {
+        let x = Tensor1::new([1., 5., 2., 3.]);
+        let masked = x.masked_softmax(&[true, false, true, true]);
+        assert_eq!(masked.0[1], 0.);
+        assert!((masked.sum() - 1.).abs() < 1e-12);
+        let unmasked = Tensor1::new([1., 2., 3.]).softmax();
+        for (a, b) in [masked.0[0], masked.0[2], masked.0[3]].iter().zip(unmasked.0.iter()) {
+            assert!((a - b).abs() < 1e-12);
+        }
+        assert_eq!(x.masked_softmax(&[true; 4]), x.softmax());
+    }
+}
+
 #[cfg(test)]
 mod benches {
     use test::Bencher;
@@ -157,6 +310,18 @@ mod benches {
         })
     }
 
+    #[bench]
+    fn conv_pass_batch(ben: &mut Bencher) {
+        let distr = rand_distr::Uniform::<f64>::new(-1., 1.);
+        let mut fft_planner = FftPlanner::new();
+        with_larger_stack(move || {
+            let inputs: Vec<_> = (0..16).map(|_| Tensor3::<_, 5, 5, 64>::rand(distr)).collect();
+            let kernels = [(); 64].map(|()| Tensor3::<_, 3, 3, 64>::rand(distr));
+            let biases = Tensor3::rand(distr);
+            ben.iter(|| Tensor3::convolution_pass_batch(&inputs, &kernels, &biases, &mut fft_planner));
+        })
+    }
+
     #[bench]
     fn full_matmul_pass(ben: &mut Bencher) {
         let distr = rand_distr::Uniform::<f64>::new(-1., 1.);
@@ -0,0 +1,297 @@
+/// Update rule which adjusts parameters based on their gradients.
+///
+/// Parameters are handed over one tensor at a time as flat slices.
+/// `slot` identifies the tensor so that the optimizer can keep state for it,
+/// so the same tensor must always be given the same slot.
+pub trait Optimizer {
+    /// Called once before the parameters of an update step.
+    fn next_step(&mut self);
+
+    /// Number of update steps taken so far.
+    fn steps(&self) -> u64;
+
+    /// Change the learning rate, for example to follow a schedule.
+    fn set_learning_rate(&mut self, learning_rate: f64);
+
+    /// Update a single parameter tensor.
+    fn update(&mut self, slot: usize, params: &mut [f64], grads: &[f64]);
+
+    /// Internal state, like moment buffers, needed to resume training.
+    fn get_save_data(&self) -> Vec<Vec<f64>>;
+
+    /// Restore state previously returned by `get_save_data`.
+    fn load_save_data(&mut self, data: Vec<Vec<f64>>);
+}
+
+/// Get the state buffer of a slot, creating it if it does not exist yet.
+fn buffer(buffers: &mut Vec<Vec<f64>>, slot: usize, len: usize) -> &mut Vec<f64> {
+    if buffers.len() <= slot {
+        buffers.resize(slot + 1, Vec::new());
+    }
+    if buffers[slot].len() != len {
+        buffers[slot] = vec![0.; len];
+    }
+    &mut buffers[slot]
+}
+
+/// The step count is saved as the first buffer.
+fn load_step(data: Option<Vec<f64>>) -> u64 {
+    data.and_then(|step| step.first().copied()).unwrap_or(0.) as u64
+}
+
+/// Plain stochastic gradient descent.
+#[derive(Clone, Debug)]
+pub struct Sgd {
+    pub learning_rate: f64,
+    step: u64,
+}
+
+impl Sgd {
+    pub fn new(learning_rate: f64) -> Sgd {
+        Sgd { learning_rate, step: 0 }
+    }
+}
+
+impl Optimizer for Sgd {
+    fn next_step(&mut self) {
+        self.step += 1;
+    }
+
+    fn steps(&self) -> u64 {
+        self.step
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn update(&mut self, _slot: usize, params: &mut [f64], grads: &[f64]) {
+        for (param, grad) in params.iter_mut().zip(grads.iter()) {
+            *param -= self.learning_rate * grad;
+        }
+    }
+
+    fn get_save_data(&self) -> Vec<Vec<f64>> {
+        vec![vec![self.step as f64]]
+    }
+
+    fn load_save_data(&mut self, data: Vec<Vec<f64>>) {
+        self.step = load_step(data.into_iter().next());
+    }
+}
+
+/// Stochastic gradient descent with (optionally Nesterov) momentum.
+#[derive(Clone, Debug)]
+pub struct Momentum {
+    pub learning_rate: f64,
+    pub momentum: f64,
+    pub nesterov: bool,
+    step: u64,
+    velocity: Vec<Vec<f64>>,
+}
+
+impl Momentum {
+    pub fn new(learning_rate: f64, momentum: f64, nesterov: bool) -> Momentum {
+        Momentum {
+            learning_rate,
+            momentum,
+            nesterov,
+            step: 0,
+            velocity: Vec::new(),
+        }
+    }
+}
+
+impl Optimizer for Momentum {
+    fn next_step(&mut self) {
+        self.step += 1;
+    }
+
+    fn steps(&self) -> u64 {
+        self.step
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn update(&mut self, slot: usize, params: &mut [f64], grads: &[f64]) {
+        let velocity = buffer(&mut self.velocity, slot, params.len());
+        for ((param, grad), vel) in params.iter_mut().zip(grads.iter()).zip(velocity.iter_mut()) {
+            *vel = self.momentum * *vel + grad;
+            let step = if self.nesterov {
+                grad + self.momentum * *vel
+            } else {
+                *vel
+            };
+            *param -= self.learning_rate * step;
+        }
+    }
+
+    fn get_save_data(&self) -> Vec<Vec<f64>> {
+        // The step count goes first, followed by the velocity.
+        let mut data = vec![vec![self.step as f64]];
+        data.extend(self.velocity.iter().cloned());
+        data
+    }
+
+    fn load_save_data(&mut self, data: Vec<Vec<f64>>) {
+        let mut iter = data.into_iter();
+        self.step = load_step(iter.next());
+        self.velocity = iter.collect();
+    }
+}
+
+/// Adam optimizer (Kingma & Ba, 2014).
+#[derive(Clone, Debug)]
+pub struct Adam {
+    pub learning_rate: f64,
+    pub beta1: f64,
+    pub beta2: f64,
+    pub epsilon: f64,
+    step: u64,
+    first_moment: Vec<Vec<f64>>,
+    second_moment: Vec<Vec<f64>>,
+}
+
+impl Adam {
+    /// Adam with the usual default hyper-parameters.
+    pub fn new(learning_rate: f64) -> Adam {
+        Adam {
+            learning_rate,
+            beta1: 0.9,
+            beta2: 0.999,
+            epsilon: 1e-8,
+            step: 0,
+            first_moment: Vec::new(),
+            second_moment: Vec::new(),
+        }
+    }
+}
+
+impl Optimizer for Adam {
+    fn next_step(&mut self) {
+        self.step += 1;
+    }
+
+    fn steps(&self) -> u64 {
+        self.step
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn update(&mut self, slot: usize, params: &mut [f64], grads: &[f64]) {
+        // Bias correction for moments initialized at zero.
+        let correction1 = 1. - self.beta1.powi(self.step as i32);
+        let correction2 = 1. - self.beta2.powi(self.step as i32);
+        let m = buffer(&mut self.first_moment, slot, params.len());
+        let v = buffer(&mut self.second_moment, slot, params.len());
+        for (((param, grad), m), v) in params
+            .iter_mut()
+            .zip(grads.iter())
+            .zip(m.iter_mut())
+            .zip(v.iter_mut())
+        {
+            *m = self.beta1 * *m + (1. - self.beta1) * grad;
+            *v = self.beta2 * *v + (1. - self.beta2) * grad * grad;
+            let m_hat = *m / correction1;
+            let v_hat = *v / correction2;
+            *param -= self.learning_rate * m_hat / (v_hat.sqrt() + self.epsilon);
+        }
+    }
+
+    fn get_save_data(&self) -> Vec<Vec<f64>> {
+        // The step count goes first, followed by both moments.
+        let mut data = vec![vec![self.step as f64]];
+        data.extend(self.first_moment.iter().cloned());
+        data.extend(self.second_moment.iter().cloned());
+        data
+    }
+
+    fn load_save_data(&mut self, data: Vec<Vec<f64>>) {
+        let mut iter = data.into_iter();
+        self.step = load_step(iter.next());
+        let mut moments: Vec<_> = iter.collect();
+        self.second_moment = moments.split_off(moments.len() / 2);
+        self.first_moment = moments;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sgd() {
+        let mut optimizer = Sgd::new(0.5);
+        let mut params = [1., 2., 3.];
+        optimizer.next_step();
+        optimizer.update(0, &mut params, &[2., 0., -2.]);
+        assert_eq!(params, [0., 2., 4.]);
+    }
+
+    #[test]
+    fn momentum() {
+        let mut optimizer = Momentum::new(1., 0.5, false);
+        let mut params = [0.];
+        for _ in 0..3 {
+            optimizer.next_step();
+            optimizer.update(0, &mut params, &[1.]);
+        }
+        // Velocity goes 1, 1.5, 1.75.
+        assert_eq!(params, [-4.25]);
+
+        let mut optimizer = Momentum::new(1., 0.5, true);
+        let mut params = [0.];
+        for _ in 0..2 {
+            optimizer.next_step();
+            optimizer.update(0, &mut params, &[1.]);
+        }
+        // Steps are 1 + 0.5 * 1 and 1 + 0.5 * 1.5.
+        assert_eq!(params, [-3.25]);
+    }
+
+    #[test]
+    fn momentum_save_and_load() {
+        let mut orig = Momentum::new(0.1, 0.9, false);
+        let mut params = [0., 0.];
+        for _ in 0..3 {
+            orig.next_step();
+            orig.update(0, &mut params, &[1., 2.]);
+        }
+        let mut optimizer = Momentum::new(0.1, 0.9, false);
+        optimizer.load_save_data(orig.get_save_data());
+        assert_eq!(optimizer.steps(), 3);
+        assert_eq!(optimizer.velocity, orig.velocity);
+    }
+
+    #[test]
+    fn adam_first_step() {
+        // The first step of Adam has a magnitude of about the learning rate.
+        let mut optimizer = Adam::new(0.1);
+        let mut params = [0., 0.];
+        optimizer.next_step();
+        optimizer.update(0, &mut params, &[100., -0.01]);
+        assert!((params[0] + 0.1).abs() < 1e-6);
+        assert!((params[1] - 0.1).abs() < 1e-4);
+    }
+
+    #[test]
+    fn adam_save_and_load() {
+        let mut orig = Adam::new(0.1);
+        let mut params = [0., 0., 0.];
+        let mut other = [0.];
+        for _ in 0..3 {
+            orig.next_step();
+            orig.update(0, &mut params, &[1., 2., 3.]);
+            orig.update(1, &mut other, &[-1.]);
+        }
+        let mut optimizer = Adam::new(0.1);
+        optimizer.load_save_data(orig.get_save_data());
+        assert_eq!(optimizer.step, orig.step);
+        assert_eq!(optimizer.first_moment, orig.first_moment);
+        assert_eq!(optimizer.second_moment, orig.second_moment);
+    }
+}
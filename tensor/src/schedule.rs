@@ -0,0 +1,129 @@
+use std::f64::consts::PI;
+
+/// Learning rate as a function of the global training step.
+#[derive(Clone, Copy, Debug)]
+pub enum Schedule {
+    Constant(f64),
+    /// Multiply the learning rate by `gamma` every `step_size` steps.
+    /// A `step_size` of zero never decays.
+    Step { initial: f64, gamma: f64, step_size: u64 },
+    /// Cosine annealing from `initial` down to `minimum` over `total_steps`.
+    /// Zero `total_steps` never decays.
+    Cosine { initial: f64, minimum: f64, total_steps: u64 },
+    /// Increase the learning rate linearly from zero for `steps` steps,
+    /// then follow `then` (which starts counting from zero again).
+    /// Zero `steps` follows `then` right away.
+    Warmup { steps: u64, then: &'static Schedule },
+}
+
+impl Schedule {
+    pub fn learning_rate(&self, step: u64) -> f64 {
+        match *self {
+            Schedule::Constant(learning_rate) => learning_rate,
+            Schedule::Step { initial, step_size: 0, .. } => initial,
+            Schedule::Step {
+                initial,
+                gamma,
+                step_size,
+            } => initial * gamma.powi((step / step_size) as i32),
+            Schedule::Cosine { initial, total_steps: 0, .. } => initial,
+            Schedule::Cosine {
+                initial,
+                minimum,
+                total_steps,
+            } => {
+                let progress = step.min(total_steps) as f64 / total_steps as f64;
+                minimum + (initial - minimum) * (1. + (PI * progress).cos()) / 2.
+            }
+            Schedule::Warmup { steps, then } => {
+                if step < steps {
+                    then.learning_rate(0) * (step + 1) as f64 / steps as f64
+                } else {
+                    then.learning_rate(step - steps)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant() {
+        assert_eq!(Schedule::Constant(0.1).learning_rate(0), 0.1);
+        assert_eq!(Schedule::Constant(0.1).learning_rate(1000), 0.1);
+    }
+
+    #[test]
+    fn step() {
+        let schedule = Schedule::Step {
+            initial: 1.,
+            gamma: 0.5,
+            step_size: 10,
+        };
+        assert_eq!(schedule.learning_rate(0), 1.);
+        assert_eq!(schedule.learning_rate(9), 1.);
+        assert_eq!(schedule.learning_rate(10), 0.5);
+        assert_eq!(schedule.learning_rate(25), 0.25);
+    }
+
+    #[test]
+    fn cosine() {
+        let schedule = Schedule::Cosine {
+            initial: 1.,
+            minimum: 0.,
+            total_steps: 100,
+        };
+        assert_eq!(schedule.learning_rate(0), 1.);
+        assert!((schedule.learning_rate(50) - 0.5).abs() < 1e-12);
+        assert!(schedule.learning_rate(100).abs() < 1e-12);
+        assert!(schedule.learning_rate(200).abs() < 1e-12);
+    }
+
+    #[test]
+    fn warmup() {
+        static AFTER: Schedule = Schedule::Constant(1.);
+        let schedule = Schedule::Warmup {
+            steps: 4,
+            then: &AFTER,
+        };
+        assert_eq!(schedule.learning_rate(0), 0.25);
+        assert_eq!(schedule.learning_rate(3), 1.);
+        assert_eq!(schedule.learning_rate(100), 1.);
+    }
+
+    #[test]
+    fn step_of_zero_steps() {
+        let schedule = Schedule::Step {
+            initial: 1.,
+            gamma: 0.5,
+            step_size: 0,
+        };
+        assert_eq!(schedule.learning_rate(0), 1.);
+        assert_eq!(schedule.learning_rate(100), 1.);
+    }
+
+    #[test]
+    fn cosine_of_zero_steps() {
+        let schedule = Schedule::Cosine {
+            initial: 1.,
+            minimum: 0.,
+            total_steps: 0,
+        };
+        assert_eq!(schedule.learning_rate(0), 1.);
+        assert_eq!(schedule.learning_rate(100), 1.);
+    }
+
+    #[test]
+    fn warmup_of_zero_steps() {
+        static AFTER: Schedule = Schedule::Constant(1.);
+        let schedule = Schedule::Warmup {
+            steps: 0,
+            then: &AFTER,
+        };
+        assert_eq!(schedule.learning_rate(0), 1.);
+        assert_eq!(schedule.learning_rate(100), 1.);
+    }
+}